@@ -0,0 +1,383 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use tracing::warn;
+
+use crate::storage::{AssetKind, Storage};
+
+/// how long and how much generated audio and images are kept
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Retention {
+    pub max_age: Option<Duration>,
+    pub device_bytes: Option<u64>,
+    pub total_bytes: Option<u64>,
+}
+
+/// files and bytes on disk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DiskUsage {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// disk usage as of the last sweep
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AssetUsage {
+    pub audio: DiskUsage,
+    pub image: DiskUsage,
+    pub devices: usize,
+    // everything removed by the janitor since startup
+    pub swept: DiskUsage,
+}
+
+/// generated files per device under a single root, served from `/assets`
+///
+/// device ids are trusted to be the checked ones of `AppContext`, they end up in paths as is
+#[derive(Debug)]
+pub struct Assets {
+    root: PathBuf,
+    retention: Retention,
+    usage: Mutex<AssetUsage>,
+}
+
+#[derive(Debug, Clone)]
+struct AssetFile {
+    kind: AssetKind,
+    device_id: String,
+    path: PathBuf,
+    bytes: u64,
+    modified: SystemTime,
+}
+
+impl Assets {
+    pub fn new(root: impl Into<PathBuf>, retention: Retention) -> Self {
+        Self {
+            root: root.into(),
+            retention,
+            usage: Mutex::new(AssetUsage::default()),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn audio_path(&self, device_id: &str, name: &str) -> PathBuf {
+        self.device_dir(AssetKind::Audio, device_id)
+            .join(format!("{}.mp3", name))
+    }
+
+    pub fn image_path(&self, device_id: &str, name: &str) -> PathBuf {
+        self.device_dir(AssetKind::Image, device_id)
+            .join(format!("{}.png", name))
+    }
+
+    /// speech shared by every device, named after the hash of what was said and how
+    pub fn speech_dir(&self) -> PathBuf {
+        self.root.join("speech")
+    }
+
+    /// a file of the device under `{kind}/{device_id}/`, none for anything that isn't one
+    pub fn device_file(&self, kind: &str, device_id: &str, file: &str) -> Option<PathBuf> {
+        let kind: AssetKind = kind.parse().ok()?;
+
+        is_file_name(file).then(|| self.device_dir(kind, device_id).join(file))
+    }
+
+    /// a file of the shared speech cache
+    pub fn speech_file(&self, file: &str) -> Option<PathBuf> {
+        is_file_name(file).then(|| self.speech_dir().join(file))
+    }
+
+    pub fn usage(&self) -> AssetUsage {
+        *self.usage.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// remove every file of the device, shared speech stays
+    pub async fn delete_device(&self, device_id: &str) -> Result<()> {
+        for kind in [AssetKind::Audio, AssetKind::Image] {
+            let dir = self.device_dir(kind, device_id);
+            if let Err(err) = tokio::fs::remove_dir_all(&dir).await {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    return Err(err.into());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// enforce the retention limits, returns what was removed
+    pub async fn sweep(&self, storage: &Storage) -> Result<DiskUsage> {
+        let root = self.root.clone();
+        let retention = self.retention;
+        let (kept, removed) = tokio::task::spawn_blocking(move || {
+            let files = scan(&root)?;
+            let (kept, removed) = expire(files, &retention, SystemTime::now());
+            for file in &removed {
+                if let Err(err) = std::fs::remove_file(&file.path) {
+                    warn!("failed to remove {}: {}", file.path.display(), err);
+                }
+            }
+            anyhow::Ok((kept, removed))
+        })
+        .await??;
+
+        let paths = removed.iter().map(|v| v.path.clone()).collect();
+        storage.forget_assets(paths).await?;
+
+        let removed = total(&removed);
+        let mut usage = self.usage.lock().unwrap_or_else(PoisonError::into_inner);
+        *usage = AssetUsage {
+            audio: total(kept.iter().filter(|v| v.kind == AssetKind::Audio)),
+            image: total(kept.iter().filter(|v| v.kind == AssetKind::Image)),
+            devices: kept
+                .iter()
+                .map(|v| v.device_id.as_str())
+                .collect::<std::collections::HashSet<_>>()
+                .len(),
+            swept: DiskUsage {
+                files: usage.swept.files + removed.files,
+                bytes: usage.swept.bytes + removed.bytes,
+            },
+        };
+
+        Ok(removed)
+    }
+
+    fn device_dir(&self, kind: AssetKind, device_id: &str) -> PathBuf {
+        self.root.join(kind.to_string()).join(device_id)
+    }
+}
+
+pub fn audio_url(device_id: &str, name: &str) -> String {
+    format!("/assets/audio/{}/{}.mp3", device_id, name)
+}
+
+pub fn image_url(device_id: &str, name: &str) -> String {
+    format!("/assets/image/{}/{}.png", device_id, name)
+}
+
+pub fn speech_url(key: &str) -> String {
+    format!("/assets/speech/{}.mp3", key)
+}
+
+// a single path segment that can't climb out of its directory
+fn is_file_name(segment: &str) -> bool {
+    !segment.is_empty()
+        && !segment.starts_with('.')
+        && segment
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+// every file under `{root}/{kind}/{device_id}/`
+fn scan(root: &Path) -> Result<Vec<AssetFile>> {
+    let mut files = vec![];
+    for kind in [AssetKind::Audio, AssetKind::Image] {
+        let dir = root.join(kind.to_string());
+        if !dir.exists() {
+            continue;
+        }
+        for device in std::fs::read_dir(dir)? {
+            let device = device?;
+            if !device.file_type()?.is_dir() {
+                continue;
+            }
+            let device_id = device.file_name().to_string_lossy().to_string();
+            for entry in std::fs::read_dir(device.path())? {
+                let entry = entry?;
+                let meta = entry.metadata()?;
+                if !meta.is_file() {
+                    continue;
+                }
+                files.push(AssetFile {
+                    kind,
+                    device_id: device_id.clone(),
+                    path: entry.path(),
+                    bytes: meta.len(),
+                    modified: meta.modified()?,
+                });
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+// split files into kept and removed, too old first, then the oldest over the size limits
+fn expire(
+    mut files: Vec<AssetFile>,
+    retention: &Retention,
+    now: SystemTime,
+) -> (Vec<AssetFile>, Vec<AssetFile>) {
+    files.sort_by_key(|v| v.modified);
+    let (mut kept, mut removed): (Vec<_>, Vec<_>) = files.into_iter().partition(|v| {
+        let age = now.duration_since(v.modified).unwrap_or_default();
+        retention.max_age.is_none_or(|max| age <= max)
+    });
+
+    if let Some(limit) = retention.device_bytes {
+        let mut devices: HashMap<String, u64> = HashMap::new();
+        for file in &kept {
+            *devices.entry(file.device_id.clone()).or_default() += file.bytes;
+        }
+        kept.retain(|v| {
+            let Some(used) = devices.get_mut(&v.device_id) else {
+                return true;
+            };
+            if *used <= limit {
+                return true;
+            }
+            *used -= v.bytes;
+            removed.push(v.clone());
+            false
+        });
+    }
+
+    if let Some(limit) = retention.total_bytes {
+        let mut used = total(&kept).bytes;
+        kept.retain(|v| {
+            if used <= limit {
+                return true;
+            }
+            used -= v.bytes;
+            removed.push(v.clone());
+            false
+        });
+    }
+
+    (kept, removed)
+}
+
+fn total<'a>(files: impl IntoIterator<Item = &'a AssetFile>) -> DiskUsage {
+    files
+        .into_iter()
+        .fold(DiskUsage::default(), |acc, v| DiskUsage {
+            files: acc.files + 1,
+            bytes: acc.bytes + v.bytes,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn file(device_id: &str, name: &str, bytes: u64, age: Duration, now: SystemTime) -> AssetFile {
+        AssetFile {
+            kind: AssetKind::Audio,
+            device_id: device_id.to_string(),
+            path: PathBuf::from(name),
+            bytes,
+            modified: now - age,
+        }
+    }
+
+    fn names(files: &[AssetFile]) -> Vec<&str> {
+        files.iter().map(|v| v.path.to_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_expire_by_age() {
+        let now = SystemTime::now();
+        let files = vec![
+            file("d1", "old", 10, 48 * HOUR, now),
+            file("d1", "new", 10, HOUR, now),
+        ];
+        let retention = Retention {
+            max_age: Some(24 * HOUR),
+            ..Default::default()
+        };
+
+        let (kept, removed) = expire(files, &retention, now);
+        assert_eq!(names(&kept), vec!["new"]);
+        assert_eq!(names(&removed), vec!["old"]);
+    }
+
+    #[test]
+    fn test_expire_by_size_oldest_first() {
+        let now = SystemTime::now();
+        let files = vec![
+            file("d1", "a2", 10, 2 * HOUR, now),
+            file("d1", "a3", 10, HOUR, now),
+            file("d1", "a1", 10, 3 * HOUR, now),
+            file("d2", "b1", 10, 4 * HOUR, now),
+        ];
+
+        // d1 may keep 20 bytes, d2 is under its limit
+        let retention = Retention {
+            device_bytes: Some(20),
+            ..Default::default()
+        };
+        let (kept, removed) = expire(files.clone(), &retention, now);
+        assert_eq!(names(&kept), vec!["b1", "a2", "a3"]);
+        assert_eq!(names(&removed), vec!["a1"]);
+
+        // globally only the newest two fit
+        let retention = Retention {
+            total_bytes: Some(20),
+            ..Default::default()
+        };
+        let (kept, removed) = expire(files, &retention, now);
+        assert_eq!(names(&kept), vec!["a2", "a3"]);
+        assert_eq!(names(&removed), vec!["b1", "a1"]);
+    }
+
+    #[test]
+    fn test_asset_files_stay_in_their_directory() {
+        let assets = Assets::new("/srv/qbot", Retention::default());
+        assert_eq!(
+            assets.device_file("audio", "d1", "a.mp3"),
+            Some(PathBuf::from("/srv/qbot/audio/d1/a.mp3"))
+        );
+        assert_eq!(assets.device_file("video", "d1", "a.mp3"), None);
+        assert_eq!(assets.device_file("audio", "d1", "../../qbot.db"), None);
+        assert_eq!(assets.device_file("audio", "d1", ".hidden"), None);
+        assert_eq!(assets.speech_file("../qbot.db"), None);
+        assert_eq!(
+            assets.speech_file("ab12.mp3"),
+            Some(PathBuf::from("/srv/qbot/speech/ab12.mp3"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sweep_and_delete_device() -> Result<()> {
+        let root = std::env::temp_dir().join(format!("qbot-assets-{}", Uuid::new_v4()));
+        let retention = Retention {
+            total_bytes: Some(4),
+            ..Default::default()
+        };
+        let assets = Assets::new(&root, retention);
+        let storage = Storage::open_in_memory()?;
+
+        let old = assets.audio_path("d1", "old");
+        let new = assets.image_path("d2", "new");
+        for path in [&old, &new] {
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::fs::write(path, b"1234")?;
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let removed = assets.sweep(&storage).await?;
+        assert_eq!(removed, DiskUsage { files: 1, bytes: 4 });
+        assert!(!old.exists());
+        let usage = assets.usage();
+        assert_eq!(usage.image, DiskUsage { files: 1, bytes: 4 });
+        assert_eq!(usage.audio, DiskUsage::default());
+        assert_eq!((usage.devices, usage.swept.files), (1, 1));
+
+        assets.delete_device("d2").await?;
+        assert!(!new.exists());
+        // nothing left to delete is fine too
+        assets.delete_device("d2").await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,483 @@
+use std::{collections::HashMap, fs, path::Path, str::FromStr};
+
+use anyhow::{anyhow, ensure, Context, Result};
+use llm_sdk::{ChatCompleteModel, ImageModel, SpeechModel, SpeechVoice, WhisperModel};
+use serde::{Deserialize, Deserializer, Serialize};
+use syntect::highlighting::ThemeSet;
+
+/// everything that shapes how Q talks, loaded from the `--config` toml file
+///
+/// every field has a default, so the file only needs the values to override
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// heading of the chat page
+    pub title: String,
+    pub user: Profile,
+    pub assistant: Profile,
+    pub prompts: Prompts,
+    pub models: Models,
+    pub speech: Speech,
+    pub markdown: Markdown,
+    pub pricing: Pricing,
+}
+
+/// how a participant shows up in the chat, `name` is also sent to the model
+///
+/// both fields are required once the section is present
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub name: String,
+    pub avatar: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Prompts {
+    /// system prompt of the request choosing a tool
+    pub tools: String,
+    pub answer: String,
+    pub write_code: String,
+    /// hint passed to whisper along with the audio
+    pub transcription: String,
+    /// descriptions the model picks a tool by
+    pub draw_image_tool: String,
+    pub write_code_tool: String,
+    pub answer_tool: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Models {
+    pub chat: ChatCompleteModel,
+    /// tried in order when `chat` keeps failing
+    pub chat_fallbacks: Vec<ChatCompleteModel>,
+    #[serde(deserialize_with = "from_str")]
+    pub whisper: WhisperModel,
+    pub speech: SpeechModelName,
+    pub image: ImageModelName,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Speech {
+    pub voice: Voice,
+    pub speed: f32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Markdown {
+    /// syntect theme used to highlight code blocks
+    pub theme: String,
+}
+
+/// list prices in USD by model name, usage is only ever an estimate of the bill
+///
+/// a `[pricing]` section overrides the built-in prices of the models it lists, the rest keep theirs
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "HashMap<String, Price>")]
+pub struct Pricing(HashMap<String, Price>);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Price {
+    /// per 1k prompt tokens
+    pub prompt: f64,
+    /// per 1k completion tokens
+    pub completion: f64,
+    /// per minute of transcribed audio
+    pub minute: f64,
+    /// per 1k characters of synthesized speech
+    pub chars: f64,
+    pub image: f64,
+}
+
+// llm-sdk enums below can't be deserialized, so the config mirrors them
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SpeechModelName {
+    #[default]
+    #[serde(rename = "tts-1")]
+    Tts1,
+    #[serde(rename = "tts-1-hd")]
+    Tts1Hd,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ImageModelName {
+    #[default]
+    #[serde(rename = "dall-e-3")]
+    DallE3,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Voice {
+    Alloy,
+    Echo,
+    Fable,
+    Onyx,
+    #[default]
+    Nova,
+    Shimmer,
+}
+
+impl Config {
+    /// load the config file, or the defaults when there is none
+    pub fn load(path: Option<impl AsRef<Path>>) -> Result<Self> {
+        let config = match path {
+            Some(path) => {
+                let path = path.as_ref();
+                let content = fs::read_to_string(path)
+                    .with_context(|| format!("failed to read config {}", path.display()))?;
+                toml::from_str(&content)
+                    .with_context(|| format!("invalid config {}", path.display()))?
+            }
+            None => Config::default(),
+        };
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for (field, profile) in [("user", &self.user), ("assistant", &self.assistant)] {
+            // the openai api only accepts these in message names
+            ensure!(
+                !profile.name.is_empty()
+                    && profile.name.len() <= 64
+                    && profile
+                        .name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-'),
+                "{}.name must be 1-64 ascii letters, digits, `_` or `-`, got {:?}",
+                field,
+                profile.name
+            );
+        }
+
+        for (field, prompt) in [
+            ("prompts.tools", &self.prompts.tools),
+            ("prompts.answer", &self.prompts.answer),
+            ("prompts.write_code", &self.prompts.write_code),
+            ("prompts.draw_image_tool", &self.prompts.draw_image_tool),
+            ("prompts.write_code_tool", &self.prompts.write_code_tool),
+            ("prompts.answer_tool", &self.prompts.answer_tool),
+        ] {
+            ensure!(!prompt.trim().is_empty(), "{} must not be empty", field);
+        }
+
+        ensure!(
+            (0.25..=4.0).contains(&self.speech.speed),
+            "speech.speed must be between 0.25 and 4.0, got {}",
+            self.speech.speed
+        );
+
+        for (model, price) in &self.pricing.0 {
+            ensure!(
+                [
+                    price.prompt,
+                    price.completion,
+                    price.minute,
+                    price.chars,
+                    price.image
+                ]
+                .iter()
+                .all(|v| *v >= 0.0),
+                "pricing.{:?} must not be negative",
+                model
+            );
+        }
+
+        let themes = ThemeSet::load_defaults();
+        ensure!(
+            themes.themes.contains_key(&self.markdown.theme),
+            "markdown.theme {:?} is not one of {:?}",
+            self.markdown.theme,
+            themes.themes.keys().collect::<Vec<_>>()
+        );
+
+        Ok(())
+    }
+}
+
+impl Models {
+    /// the chat model first, then its fallbacks
+    pub fn chat_models(&self) -> Vec<ChatCompleteModel> {
+        let mut models = vec![self.chat];
+        models.extend(self.chat_fallbacks.iter().filter(|v| **v != self.chat));
+        models
+    }
+}
+
+impl Pricing {
+    /// what `model` costs, nothing when it isn't listed
+    pub fn get(&self, model: &str) -> Price {
+        self.0.get(model).copied().unwrap_or_default()
+    }
+}
+
+// a model missing from the table would cost nothing, and slip past the spending caps
+impl From<HashMap<String, Price>> for Pricing {
+    fn from(listed: HashMap<String, Price>) -> Self {
+        let mut pricing = Self::default();
+        pricing.0.extend(listed);
+        pricing
+    }
+}
+
+impl Profile {
+    pub fn new(name: impl Into<String>, avatar: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            avatar: avatar.into(),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            title: "Ava Bot".to_string(),
+            user: Profile::new("zheng", "https://i.pravatar.cc/300"),
+            assistant: Profile::new("Q", "/public/images/q-bot.png"),
+            prompts: Prompts::default(),
+            models: Models::default(),
+            speech: Speech::default(),
+            markdown: Markdown::default(),
+            pricing: Pricing::default(),
+        }
+    }
+}
+
+impl Default for Prompts {
+    fn default() -> Self {
+        Self {
+            tools: "I can do to help you?".to_string(),
+            answer: "I can help answer anything you'r like to chat.".to_string(),
+            write_code: "I'm an expert on coding, I'll write code for you in markdown format based on your prompt".to_string(),
+            transcription: "If audio language is Chinese, please use simplified chinese"
+                .to_string(),
+            draw_image_tool: "Draw images based on the prompt.".to_string(),
+            write_code_tool: "Write code based on the prompt.".to_string(),
+            answer_tool: "Just reply based on the prompt, please use simplified chinese."
+                .to_string(),
+        }
+    }
+}
+
+impl Default for Speech {
+    fn default() -> Self {
+        Self {
+            voice: Voice::default(),
+            speed: 1.0,
+        }
+    }
+}
+
+// openai list prices as of the 1106 models
+impl Default for Pricing {
+    fn default() -> Self {
+        let tokens = |prompt, completion| Price {
+            prompt,
+            completion,
+            ..Default::default()
+        };
+        Self(HashMap::from([
+            ("gpt-3.5-turbo-1106".to_string(), tokens(0.001, 0.002)),
+            ("gpt-3.5-turbo-instruct".to_string(), tokens(0.0015, 0.002)),
+            ("gpt-4-1106-preview".to_string(), tokens(0.01, 0.03)),
+            ("gpt-4-1106-vision-preview".to_string(), tokens(0.01, 0.03)),
+            (
+                "whisper-1".to_string(),
+                Price {
+                    minute: 0.006,
+                    ..Default::default()
+                },
+            ),
+            (
+                "tts-1".to_string(),
+                Price {
+                    chars: 0.015,
+                    ..Default::default()
+                },
+            ),
+            (
+                "tts-1-hd".to_string(),
+                Price {
+                    chars: 0.03,
+                    ..Default::default()
+                },
+            ),
+            (
+                "dall-e-3".to_string(),
+                Price {
+                    image: 0.04,
+                    ..Default::default()
+                },
+            ),
+        ]))
+    }
+}
+
+impl Default for Markdown {
+    fn default() -> Self {
+        Self {
+            theme: "Solarized (dark)".to_string(),
+        }
+    }
+}
+
+impl From<SpeechModelName> for SpeechModel {
+    fn from(value: SpeechModelName) -> Self {
+        match value {
+            SpeechModelName::Tts1 => SpeechModel::Tts1,
+            SpeechModelName::Tts1Hd => SpeechModel::Tts1Hd,
+        }
+    }
+}
+
+impl From<ImageModelName> for ImageModel {
+    fn from(value: ImageModelName) -> Self {
+        match value {
+            ImageModelName::DallE3 => ImageModel::DallE3,
+        }
+    }
+}
+
+impl From<Voice> for SpeechVoice {
+    fn from(value: Voice) -> Self {
+        match value {
+            Voice::Alloy => SpeechVoice::Alloy,
+            Voice::Echo => SpeechVoice::Echo,
+            Voice::Fable => SpeechVoice::Fable,
+            Voice::Onyx => SpeechVoice::Onyx,
+            Voice::Nova => SpeechVoice::Nova,
+            Voice::Shimmer => SpeechVoice::Shimmer,
+        }
+    }
+}
+
+/// the name a model goes by on the wire, e.g. `gpt-3.5-turbo-1106`
+pub fn model_name(model: impl Serialize + std::fmt::Debug) -> String {
+    match serde_json::to_value(&model) {
+        Ok(serde_json::Value::String(v)) => v,
+        _ => format!("{:?}", model),
+    }
+}
+
+fn from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse()
+        .map_err(|_| serde::de::Error::custom(anyhow!("unknown value {:?}", s)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_config_is_valid() {
+        Config::default().validate().unwrap();
+    }
+
+    #[test]
+    fn test_example_config_is_valid() -> Result<()> {
+        let config = Config::load(Some(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/config.example.toml"
+        )))?;
+        assert_eq!(config.assistant.name, "Q");
+        Ok(())
+    }
+
+    #[test]
+    fn test_partial_config_keeps_defaults() -> Result<()> {
+        let config: Config = toml::from_str(
+            r#"
+            [assistant]
+            name = "Ava"
+            avatar = "/public/images/q-bot1.png"
+
+            [models]
+            chat = "gpt-4-1106-preview"
+            speech = "tts-1-hd"
+
+            [speech]
+            voice = "onyx"
+            "#,
+        )?;
+        config.validate()?;
+
+        assert_eq!(config.assistant.name, "Ava");
+        assert_eq!(config.user.name, "zheng");
+        assert_eq!(config.title, "Ava Bot");
+        assert_eq!(config.prompts.answer_tool, Prompts::default().answer_tool);
+        assert_eq!(config.models.chat, ChatCompleteModel::Gpt4Turbo);
+        assert_eq!(config.models.whisper, WhisperModel::Whisper1);
+        assert_eq!(config.models.speech, SpeechModelName::Tts1Hd);
+        assert_eq!(config.speech.voice, Voice::Onyx);
+        assert_eq!(config.markdown.theme, "Solarized (dark)");
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_config_is_rejected() {
+        let config: Config = toml::from_str("[speech]\nspeed = 10.0").unwrap();
+        assert!(config.validate().is_err());
+
+        let config: Config = toml::from_str("[markdown]\ntheme = \"nope\"").unwrap();
+        assert!(config.validate().is_err());
+
+        let config: Config =
+            toml::from_str("[user]\nname = \"zheng wang\"\navatar = \"\"").unwrap();
+        assert!(config.validate().is_err());
+
+        assert!(toml::from_str::<Config>("[models]\nwhisper = \"whisper-2\"").is_err());
+        assert!(toml::from_str::<Config>("unknown = 1").is_err());
+    }
+
+    #[test]
+    fn test_chat_models_in_fallback_order() -> Result<()> {
+        let config: Config = toml::from_str(
+            r#"
+            [models]
+            chat = "gpt-4-1106-preview"
+            chat_fallbacks = ["gpt-4-1106-preview", "gpt-3.5-turbo-1106"]
+            "#,
+        )?;
+
+        assert_eq!(
+            config.models.chat_models(),
+            vec![ChatCompleteModel::Gpt4Turbo, ChatCompleteModel::Gpt3Turbo]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_pricing_overrides_only_what_it_lists() -> Result<()> {
+        let config: Config = toml::from_str(
+            r#"
+            [pricing]
+            "tts-1" = { chars = 0.02 }
+            "my-model" = { prompt = 0.5 }
+            "#,
+        )?;
+
+        let defaults = Pricing::default();
+        assert_eq!(config.pricing.get("tts-1").chars, 0.02);
+        assert_eq!(config.pricing.get("my-model").prompt, 0.5);
+        assert_eq!(
+            config.pricing.get("gpt-4-1106-preview"),
+            defaults.get("gpt-4-1106-preview")
+        );
+        assert_eq!(config.pricing.get("tts-1-hd"), defaults.get("tts-1-hd"));
+        Ok(())
+    }
+}
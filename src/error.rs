@@ -1,27 +1,184 @@
 use askama_axum::{IntoResponse, Response};
-use axum::http::StatusCode;
+use axum::{
+    extract::{
+        multipart::{MultipartError, MultipartRejection},
+        rejection::{JsonRejection, QueryRejection},
+    },
+    http::StatusCode,
+    Json,
+};
+use serde_json::json;
+use thiserror::Error;
+use tracing::{error, warn};
 
-// Make our own error that wraps `anyhow::Error`.
-pub struct AppError(anyhow::Error);
+/// what a request can fail with, each kind has its status code and a message fit for users
+#[derive(Debug, Error)]
+pub enum AppError {
+    /// the request itself is wrong, the message says how
+    #[error("{0}")]
+    BadInput(String),
+    #[error("cookie `device_id` is missing")]
+    MissingCookie,
+    #[error("the request is too large")]
+    TooLarge,
+    #[error("not found")]
+    NotFound,
+    #[error("still answering the previous question")]
+    Busy,
+    /// a limit of ours was hit, the message says which
+    #[error("{0}")]
+    RateLimited(String),
+    /// the model provider failed
+    #[error("upstream failed: {0:#}")]
+    Upstream(anyhow::Error),
+    /// the model provider turned the request down, asking again won't change that
+    #[error("upstream rejected the request: {0:#}")]
+    Rejected(anyhow::Error),
+    #[error("upstream timed out: {0:#}")]
+    Timeout(anyhow::Error),
+    #[error("storage failed: {0:#}")]
+    Storage(anyhow::Error),
+    #[error(transparent)]
+    Internal(anyhow::Error),
+}
+
+impl AppError {
+    pub fn bad_input(msg: impl Into<String>) -> Self {
+        Self::BadInput(msg.into())
+    }
+
+    // what axum turned away before the handler ran, by the status it would have answered with
+    fn rejected(status: StatusCode, msg: String) -> Self {
+        match status {
+            StatusCode::PAYLOAD_TOO_LARGE => Self::TooLarge,
+            _ => Self::BadInput(msg),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::BadInput(_) | Self::MissingCookie => StatusCode::BAD_REQUEST,
+            Self::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::Busy => StatusCode::CONFLICT,
+            Self::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::Upstream(_) | Self::Rejected(_) => StatusCode::BAD_GATEWAY,
+            Self::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Self::Storage(_) | Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::BadInput(_) => "bad_input",
+            Self::MissingCookie => "missing_cookie",
+            Self::TooLarge => "too_large",
+            Self::NotFound => "not_found",
+            Self::Busy => "busy",
+            Self::RateLimited(_) => "rate_limited",
+            Self::Upstream(_) => "upstream",
+            Self::Rejected(_) => "rejected",
+            Self::Timeout(_) => "timeout",
+            Self::Storage(_) => "storage",
+            Self::Internal(_) => "internal",
+        }
+    }
+
+    /// what users are told, upstream and internal details stay in the logs
+    pub fn message(&self) -> String {
+        match self {
+            Self::Upstream(_) => "the AI service failed, try again later".to_string(),
+            Self::Rejected(_) => "the AI service turned the request down".to_string(),
+            Self::Timeout(_) => "the AI service took too long, try again later".to_string(),
+            Self::Storage(_) => "the conversation couldn't be saved or loaded".to_string(),
+            Self::Internal(_) => "something went wrong".to_string(),
+            _ => self.to_string(),
+        }
+    }
+}
 
 // Tell axum how to convert `AppError` into a response.
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong: {}", self.0),
-        )
-            .into_response()
-    }
-}
-
-// This enables using `?` on functions that return `Result<_, anyhow::Error>` to turn them into
-// `Result<_, AppError>`. That way you don't need to do that manually.
-impl<E> From<E> for AppError
-where
-    E: Into<anyhow::Error>,
-{
-    fn from(err: E) -> Self {
-        Self(err.into())
+        let status = self.status();
+        if status.is_server_error() {
+            error!("request failed: {:#}", self);
+        } else {
+            warn!("request rejected: {}", self);
+        }
+
+        let body = json!({ "status": "error", "code": self.code(), "message": self.message() });
+        (status, Json(body)).into_response()
+    }
+}
+
+// errors raised as `AppError` deep in the pipeline keep their kind, the rest is sorted out here
+impl From<anyhow::Error> for AppError {
+    fn from(err: anyhow::Error) -> Self {
+        let err = match err.downcast::<AppError>() {
+            Ok(err) => return err,
+            Err(err) => err,
+        };
+        if let Some(err) = err.downcast_ref::<MultipartError>() {
+            return Self::rejected(err.status(), err.body_text());
+        }
+        if err.is::<tokio::time::error::Elapsed>() {
+            return Self::Timeout(err);
+        }
+
+        Self::Internal(err)
+    }
+}
+
+// used with `WithRejection`, so malformed requests get the same json body as any other error
+impl From<JsonRejection> for AppError {
+    fn from(err: JsonRejection) -> Self {
+        Self::rejected(err.status(), err.body_text())
+    }
+}
+
+impl From<MultipartRejection> for AppError {
+    fn from(err: MultipartRejection) -> Self {
+        Self::rejected(err.status(), err.body_text())
+    }
+}
+
+impl From<QueryRejection> for AppError {
+    fn from(err: QueryRejection) -> Self {
+        Self::rejected(err.status(), err.body_text())
+    }
+}
+
+impl From<std::io::Error> for AppError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Internal(err.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_errors_keep_their_kind() {
+        let err: anyhow::Error = AppError::bad_input("expected an audio field").into();
+        let err = AppError::from(err.context("reading the upload"));
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(err.message(), "expected an audio field");
+
+        let err = AppError::from(anyhow!("boom"));
+        assert_eq!(err.code(), "internal");
+    }
+
+    #[test]
+    fn test_upstream_details_are_hidden() {
+        let err = AppError::Upstream(anyhow!("API failed: invalid api key sk-123"));
+        assert_eq!(err.status(), StatusCode::BAD_GATEWAY);
+        assert!(!err.message().contains("sk-123"));
+        assert!(err.to_string().contains("sk-123"));
+
+        let err = AppError::Rejected(anyhow!("API failed: invalid api key sk-123"));
+        assert!(!err.message().contains("sk-123"));
     }
 }
@@ -0,0 +1,283 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use dashmap::DashMap;
+use tokio::sync::broadcast;
+
+use crate::handlers::AssistantEvent;
+
+// events kept per device for browsers reconnecting with `Last-Event-ID`
+const REPLAY_EVENTS: usize = 256;
+// events a slow subscriber may fall behind before it has to resync
+const MAX_EVENTS: usize = 128;
+
+/// per device event channels, created on first use by either the browser or the assistant
+#[derive(Debug)]
+pub struct EventRegistry {
+    channels: DashMap<String, Arc<EventChannel>>,
+    idle_timeout: Duration,
+}
+
+/// outgoing events of one device, numbered and kept around for reconnecting browsers
+#[derive(Debug)]
+pub struct EventChannel {
+    sender: broadcast::Sender<SequencedEvent>,
+    history: Mutex<History>,
+}
+
+/// an event with its per device id, ids start at 1 and only go up
+#[derive(Debug, Clone)]
+pub struct SequencedEvent {
+    pub id: u64,
+    pub event: AssistantEvent,
+}
+
+/// what a reconnecting browser missed
+#[derive(Debug)]
+pub enum Replay {
+    Events(Vec<SequencedEvent>),
+    /// too much was missed, or the ids are from before a restart; `last_id` is the latest id
+    Resync {
+        last_id: u64,
+    },
+}
+
+#[derive(Debug)]
+struct History {
+    last_id: u64,
+    events: VecDeque<SequencedEvent>,
+    // last send or subscribe
+    last_active: Instant,
+}
+
+impl EventRegistry {
+    /// channels without listeners are dropped once they have been quiet for `idle_timeout`
+    pub fn new(idle_timeout: Duration) -> Self {
+        Self {
+            channels: DashMap::new(),
+            idle_timeout,
+        }
+    }
+
+    pub fn channel(&self, device_id: &str) -> Arc<EventChannel> {
+        if let Some(channel) = self.channels.get(device_id) {
+            return channel.clone();
+        }
+
+        self.channels
+            .entry(device_id.to_string())
+            .or_insert_with(|| Arc::new(EventChannel::new()))
+            .clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.channels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.channels.is_empty()
+    }
+
+    /// drop idle channels, returns how many were dropped
+    pub fn evict_idle(&self) -> usize {
+        let before = self.channels.len();
+        // a handler still holding the channel may send on it any time
+        self.channels.retain(|_, channel| {
+            Arc::strong_count(channel) > 1 || !channel.is_idle(self.idle_timeout)
+        });
+
+        before.saturating_sub(self.channels.len())
+    }
+}
+
+impl EventChannel {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(MAX_EVENTS);
+        Self {
+            sender,
+            history: Mutex::new(History {
+                last_id: 0,
+                events: VecDeque::new(),
+                last_active: Instant::now(),
+            }),
+        }
+    }
+
+    /// browsers currently listening
+    pub fn subscribers(&self) -> usize {
+        self.sender.receiver_count()
+    }
+
+    fn is_idle(&self, timeout: Duration) -> bool {
+        let last_active = match self.history.lock() {
+            Ok(history) => history.last_active,
+            // a poisoned channel is of no use to anyone
+            Err(_) => return true,
+        };
+
+        self.subscribers() == 0 && last_active.elapsed() >= timeout
+    }
+
+    /// number and buffer the event, then push it to whoever is listening
+    pub fn send(&self, event: AssistantEvent) -> Result<()> {
+        let mut history = self.lock()?;
+        history.last_active = Instant::now();
+        history.last_id += 1;
+        let event = SequencedEvent {
+            id: history.last_id,
+            event,
+        };
+        if history.events.len() == REPLAY_EVENTS {
+            history.events.pop_front();
+        }
+        history.events.push_back(event.clone());
+
+        // nobody listening is fine, the event is in the replay buffer
+        let _ = self.sender.send(event);
+
+        Ok(())
+    }
+
+    /// listen to new events, and replay the ones after `last_id` when reconnecting
+    pub fn subscribe(
+        &self,
+        last_id: Option<u64>,
+    ) -> Result<(Replay, broadcast::Receiver<SequencedEvent>)> {
+        // both under the lock, so nothing is sent between the replay and the live events
+        let mut history = self.lock()?;
+        history.last_active = Instant::now();
+        let rx = self.sender.subscribe();
+        let Some(last_id) = last_id else {
+            return Ok((Replay::Events(vec![]), rx));
+        };
+
+        let oldest = history.events.front().map(|v| v.id).unwrap_or(1);
+        let replay = if last_id > history.last_id || last_id + 1 < oldest {
+            Replay::Resync {
+                last_id: history.last_id,
+            }
+        } else {
+            let events = history
+                .events
+                .iter()
+                .filter(|v| v.id > last_id)
+                .cloned()
+                .collect();
+            Replay::Events(events)
+        };
+
+        Ok((replay, rx))
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, History>> {
+        self.history
+            .lock()
+            .map_err(|_| anyhow!("event channel lock poisoned"))
+    }
+}
+
+impl Default for EventChannel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::SignalEvent;
+
+    fn ids(replay: Replay) -> Vec<u64> {
+        match replay {
+            Replay::Events(events) => events.iter().map(|v| v.id).collect(),
+            Replay::Resync { .. } => panic!("expect a replay, got a resync"),
+        }
+    }
+
+    fn send(channel: &EventChannel, n: usize) {
+        for _ in 0..n {
+            channel.send(SignalEvent::Complete.into()).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay_after_last_id() -> Result<()> {
+        let channel = EventChannel::new();
+        send(&channel, 3);
+
+        let (replay, _) = channel.subscribe(None)?;
+        assert!(ids(replay).is_empty());
+        let (replay, _) = channel.subscribe(Some(1))?;
+        assert_eq!(ids(replay), vec![2, 3]);
+        let (replay, mut rx) = channel.subscribe(Some(3))?;
+        assert!(ids(replay).is_empty());
+
+        send(&channel, 1);
+        assert_eq!(rx.recv().await?.id, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resync_when_too_far_behind() -> Result<()> {
+        let channel = EventChannel::new();
+        send(&channel, REPLAY_EVENTS + 2);
+
+        let (replay, _) = channel.subscribe(Some(2))?;
+        assert_eq!(ids(replay).len(), REPLAY_EVENTS);
+        let (replay, _) = channel.subscribe(Some(1))?;
+        assert!(
+            matches!(replay, Replay::Resync { last_id } if last_id == REPLAY_EVENTS as u64 + 2)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_registry_evicts_idle_channels() -> Result<()> {
+        let registry = EventRegistry::new(Duration::ZERO);
+
+        // the assistant may send before the browser listens
+        registry
+            .channel("sender")
+            .send(SignalEvent::Complete.into())?;
+        let listener = registry.channel("listener");
+        let (_, rx) = listener.subscribe(None)?;
+        assert_eq!(listener.subscribers(), 1);
+        drop(listener);
+        let busy = registry.channel("busy");
+        assert_eq!(registry.len(), 3);
+
+        assert_eq!(registry.evict_idle(), 1);
+        assert_eq!(registry.len(), 2);
+
+        drop((rx, busy));
+        assert_eq!(registry.evict_idle(), 2);
+        assert!(registry.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_registry_keeps_recently_active_channels() -> Result<()> {
+        let registry = EventRegistry::new(Duration::from_secs(60));
+        registry.channel("d").send(SignalEvent::Complete.into())?;
+
+        assert_eq!(registry.evict_idle(), 0);
+        let (replay, _) = registry.channel("d").subscribe(Some(0))?;
+        assert_eq!(ids(replay), vec![1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resync_when_ids_are_unknown() -> Result<()> {
+        // e.g. the browser kept the id of a server that has since restarted
+        let channel = EventChannel::new();
+        send(&channel, 1);
+
+        let (replay, _) = channel.subscribe(Some(100))?;
+        assert!(matches!(replay, Replay::Resync { last_id: 1 }));
+        Ok(())
+    }
+}
@@ -1,12 +1,12 @@
-use axum::{
-    async_trait,
-    extract::FromRequestParts,
-    http::{request::Parts, StatusCode},
-};
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
 use axum_extra::extract::CookieJar;
+use uuid::Uuid;
+
+use crate::error::AppError;
 
 #[derive(Debug, Clone)]
 pub struct AppContext {
+    /// always a uuid, so it's safe to build file paths from
     pub device_id: String,
 }
 
@@ -15,17 +15,44 @@ impl<S> FromRequestParts<S> for AppContext
 where
     S: Send + Sync,
 {
-    type Rejection = (StatusCode, &'static str);
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let jar = CookieJar::from_headers(&parts.headers);
+
+        let Some(device_id) = jar.get("device_id") else {
+            return Err(AppError::MissingCookie);
+        };
+        if !is_device_id(device_id.value()) {
+            return Err(AppError::bad_input("invalid device_id cookie"));
+        }
+
+        Ok(AppContext {
+            device_id: device_id.value().to_string(),
+        })
+    }
+}
+
+/// device ids are handed out as hyphenated uuids, anything else is forged
+pub fn is_device_id(v: &str) -> bool {
+    v.len() == 36 && Uuid::try_parse(v).is_ok()
+}
 
-    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-        let jar = CookieJar::from_request_parts(parts, state).await.unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        if let Some(device_id) = jar.get("device_id") {
-            Ok(AppContext {
-                device_id: device_id.value().to_string(),
-            })
-        } else {
-            Err((StatusCode::BAD_REQUEST, "Cookie `device_id` is missing"))
+    #[test]
+    fn test_only_uuids_are_device_ids() {
+        assert!(is_device_id(&Uuid::new_v4().to_string()));
+        for v in [
+            "",
+            "..",
+            "../..",
+            "a/b",
+            &Uuid::new_v4().simple().to_string(),
+        ] {
+            assert!(!is_device_id(v), "{:?}", v);
         }
     }
 }
@@ -0,0 +1,51 @@
+use std::{path::PathBuf, sync::Arc};
+
+use axum::{
+    body::{self, Body},
+    extract::{Path, State},
+    http::Request,
+    response::Response,
+};
+use tower_http::services::ServeFile;
+
+use crate::{error::AppError, extractors::AppContext, AppState};
+
+/// audio or an image, only for the device it was generated for
+pub async fn asset_handler(
+    context: AppContext,
+    Path((kind, device_id, file)): Path<(String, String, String)>,
+    State(state): State<Arc<AppState>>,
+    req: Request<Body>,
+) -> Result<Response, AppError> {
+    // other devices' files don't exist as far as the caller can tell
+    if device_id != context.device_id {
+        return Err(AppError::NotFound);
+    }
+    let Some(path) = state.assets.device_file(&kind, &context.device_id, &file) else {
+        return Err(AppError::NotFound);
+    };
+
+    serve(path, req).await
+}
+
+/// cached speech is shared, but only served to the devices it was spoken to
+pub async fn speech_asset_handler(
+    context: AppContext,
+    Path(file): Path<String>,
+    State(state): State<Arc<AppState>>,
+    req: Request<Body>,
+) -> Result<Response, AppError> {
+    let Some(path) = state.assets.speech_file(&file) else {
+        return Err(AppError::NotFound);
+    };
+    if !state.storage.owns_asset(&context.device_id, &path).await? {
+        return Err(AppError::NotFound);
+    }
+
+    serve(path, req).await
+}
+
+async fn serve(path: PathBuf, req: Request<Body>) -> Result<Response, AppError> {
+    let res = ServeFile::new(path).try_call(req).await?;
+    Ok(res.map(body::boxed))
+}
@@ -1,249 +1,800 @@
-use super::{AssistantEvent, AssistantStep, SignalEvent, SpeechResult};
+use super::{
+    AssistantEvent, AssistantStep, ChatReplyData, RetryAttempt, SignalEvent, SpeechResult,
+};
 use crate::{
-    audio_path, audio_url,
+    assets::{audio_url, image_url, speech_url},
+    config::model_name,
     error::AppError,
+    events::EventChannel,
     extractors::AppContext,
     handlers::{ChatInputEvent, ChatInputSkeletonEvent, ChatReplyEvent, ChatReplySkeletonEvent},
-    image_path, image_url,
+    llm::{is_retryable, retry, timed},
+    memory::{estimate_tokens, Turn},
+    speech::{split_sentences, SpeechCache},
+    storage::AssetKind,
+    tasks::Stopped,
     tools::{
-        tool_completion_request, AnswerCodeArgs, AssistantTool, DrawImageArgs, DrawImageResult,
-        WriteCodeArgs, WriteCodeResult,
+        all_tools, completion_request, tool_completion_request, AnswerCodeArgs, AssistantTool,
+        DrawImageArgs, DrawImageResult, WriteCodeArgs, WriteCodeResult, MAX_IMAGES,
     },
+    usage::Usage,
     AppState,
 };
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, ensure, Result};
 use askama_axum::IntoResponse;
 use axum::{
-    extract::{Multipart, State},
+    extract::{Multipart, Path, State},
     Json,
 };
+use axum_extra::extract::WithRejection;
 use base64::{engine::general_purpose::STANDARD, Engine};
 use comrak::{markdown_to_html_with_plugins, plugins::syntect::SyntectAdapter};
+use futures::{
+    future::{join_all, try_join_all},
+    stream, StreamExt, TryStreamExt,
+};
 use llm_sdk::{
-    ChatCompletionChoice, ChatCompletionMessage, ChatCompletionRequest, CreateImageRequestBuilder,
-    ImageResponseFormat, LlmSdk, SpeechRequest, WhisperRequestBuilder, WhisperRequestType,
+    ChatCompleteModel, ChatCompletionChoice, ChatCompletionMessage, CreateImageRequestBuilder,
+    CreateImageResponse, FinishReason, ImageResponseFormat, SpeechRequestBuilder, Tool, ToolCall,
+    WhisperRequestBuilder, WhisperRequestType, WhisperResponseFormat,
 };
+use serde::{de::DeserializeOwned, Deserialize};
 use serde_json::json;
-use std::sync::Arc;
-use tokio::{fs, sync::broadcast};
-use tracing::info;
+use std::{
+    future::Future,
+    path::PathBuf,
+    pin::pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::fs;
+use tracing::{info, warn};
 use uuid::Uuid;
 
+#[derive(Debug, Deserialize)]
+pub struct TextInput {
+    text: String,
+    // typed questions are usually asked where Q shouldn't talk out loud
+    #[serde(default)]
+    speech: bool,
+}
+
+/// largest request body, and so recording, a device may upload
+pub const MAX_UPLOAD_BYTES: usize = 2 * 1024 * 1024;
+
+// rounds of tool calls a single request may go through before Q has to answer
+const MAX_TOOL_ITERATIONS: usize = 3;
+// speech segments of a reply synthesized at the same time
+const SPEECH_PARALLELISM: usize = 4;
+// how often a streaming reply is re-rendered and pushed to the browser
+const STREAM_INTERVAL: Duration = Duration::from_millis(100);
+
 pub async fn assistant_handler(
     context: AppContext,
     State(state): State<Arc<AppState>>,
-    multipart: Multipart,
+    WithRejection(multipart, _): WithRejection<Multipart, AppError>,
 ) -> Result<impl IntoResponse, AppError> {
     let device_id = &context.device_id;
     info!("start assist for {}", device_id);
 
-    // chat content sender
-    let event_sender = state
-        .events
-        .get(device_id)
-        .ok_or_else(|| anyhow!("device_id not found for chat sender"))?
-        .clone();
+    let id = Uuid::new_v4().to_string();
+    let event_sender = state.events.channel(device_id);
+    let work = async {
+        event_sender.send(in_audio_upload())?;
+        let audio = read_audio(multipart).await?;
+        assist_voice(&event_sender, &state, device_id, &id, audio, true).await
+    };
+    let ret = run_admitted(&state, device_id, &id, work).await;
 
-    let llm = &state.llm;
+    status(&event_sender, ret)
+}
 
-    let _ = match process(&event_sender, llm, device_id, multipart).await {
-        Err(err) => {
-            event_sender.send(error(err.to_string()).into())?;
-            return Ok(Json(json!({"status":"error"})));
-        }
-        _ => {}
-    };
+/// typed question, skips the transcription and only speaks the reply when asked to
+pub async fn assistant_text_handler(
+    context: AppContext,
+    State(state): State<Arc<AppState>>,
+    WithRejection(Json(input), _): WithRejection<Json<TextInput>, AppError>,
+) -> Result<impl IntoResponse, AppError> {
+    let device_id = &context.device_id;
+    info!("start text assist for {}", device_id);
 
-    Ok(Json(json!({"status":"done"})))
+    let id = Uuid::new_v4().to_string();
+    let event_sender = state.events.channel(device_id);
+    let work = assist_text(
+        &event_sender,
+        &state,
+        device_id,
+        &id,
+        &input.text,
+        input.speech,
+    );
+    let ret = run_admitted(&state, device_id, &id, work).await;
+
+    status(&event_sender, ret)
 }
 
-async fn process(
-    event_sender: &broadcast::Sender<AssistantEvent>,
-    llm: &LlmSdk,
+/// stop a request of this device, whatever step it is in
+pub async fn cancel_handler(
+    context: AppContext,
+    Path(id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let device_id = &context.device_id;
+    if !state.tasks.cancel(device_id, &id) {
+        return Err(AppError::NotFound);
+    }
+
+    info!("user {} cancelled {}", device_id, id);
+    Ok(Json(json!({"status":"cancelled"})))
+}
+
+/// run a request unless a limit turns it away, before it can interrupt or queue behind another
+pub(crate) async fn run_admitted<F>(
+    state: &AppState,
     device_id: &str,
-    mut multipart: Multipart,
-) -> Result<()> {
-    let id = Uuid::new_v4().to_string();
+    id: &str,
+    work: F,
+) -> Result<Result<()>, Stopped>
+where
+    F: Future<Output = Result<()>>,
+{
+    let admitted = state.limits.admit(&state.storage, device_id).await;
+    match admitted {
+        Ok(()) => state.tasks.run(device_id, id, work).await,
+        Err(err) => Ok(Err(err)),
+    }
+}
+
+/// failures are signalled to the browser, with the same message the response carries
+fn status(
+    event_sender: &EventChannel,
+    ret: Result<Result<()>, Stopped>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let status = match ret {
+        Ok(Ok(())) => "done",
+        Ok(Err(err)) => {
+            let err = AppError::from(err);
+            event_sender.send(failed(&err))?;
+            return Err(err);
+        }
+        Err(reason) => {
+            event_sender.send(stopped(reason))?;
+            match reason {
+                Stopped::Cancelled => "cancelled",
+                Stopped::Interrupted => "interrupted",
+                Stopped::Busy => return Err(AppError::Busy),
+            }
+        }
+    };
 
-    event_sender.send(in_audio_upload())?;
+    Ok(Json(json!({ "status": status })))
+}
 
+async fn read_audio(mut multipart: Multipart) -> Result<Vec<u8>> {
     let Some(field) = multipart.next_field().await? else {
-        return Err(anyhow!("expected an audio field"))?;
+        bail!(AppError::bad_input("expected an audio field"));
     };
     let data = match field.name() {
-        Some(name) if name == "audio" => field.bytes().await?,
-        _ => return Err(anyhow!("expected an audio field"))?,
+        Some("audio") => field.bytes().await?,
+        _ => bail!(AppError::bad_input("expected an audio field")),
     };
 
-    info!("audio buffer size {}", data.len());
+    Ok(data.to_vec())
+}
+
+/// transcribe a recorded question and answer it
+pub(crate) async fn assist_voice(
+    event_sender: &EventChannel,
+    state: &AppState,
+    device_id: &str,
+    id: &str,
+    audio: Vec<u8>,
+    spoken: bool,
+) -> Result<()> {
+    info!("audio buffer size {}", audio.len());
+    ensure!(
+        !audio.is_empty(),
+        AppError::bad_input("expected some audio")
+    );
 
     // 语音转文字
     event_sender.send(in_transcription())?;
-    event_sender.send(ChatInputSkeletonEvent::new(&id).into())?;
-    let input = transcript(llm, data.to_vec()).await?;
+    event_sender.send(ChatInputSkeletonEvent::new(id, &state.config.user).into())?;
+    let input = transcript(state, event_sender, device_id, audio).await?;
     info!("> input {}", &input);
-    event_sender.send(ChatInputEvent::new(&id, &input).into())?;
+
+    process(event_sender, state, device_id, id, &input, spoken).await
+}
+
+/// answer a typed question
+pub(crate) async fn assist_text(
+    event_sender: &EventChannel,
+    state: &AppState,
+    device_id: &str,
+    id: &str,
+    text: &str,
+    spoken: bool,
+) -> Result<()> {
+    let text = text.trim();
+    ensure!(!text.is_empty(), AppError::bad_input("expected some text"));
+
+    event_sender.send(ChatInputSkeletonEvent::new(id, &state.config.user).into())?;
+    process(event_sender, state, device_id, id, text, spoken).await
+}
+
+/// answer `input`, the input skeleton has already been sent; replies are spoken if `spoken`
+async fn process(
+    event_sender: &EventChannel,
+    state: &AppState,
+    device_id: &str,
+    id: &str,
+    input: &str,
+    spoken: bool,
+) -> Result<()> {
+    let config = &state.config;
+    let input_event = ChatInputEvent::new(id, input);
+    state.storage.save_input(device_id, &input_event).await?;
+    event_sender.send(input_event.into())?;
+
+    // 对话历史
+    let history = state.memory.messages(device_id, &config.user.name);
+    let mut turn = Turn::new(input);
 
     // choice, 选择模型
     event_sender.send(in_thinking())?;
-    event_sender.send(ChatReplySkeletonEvent::new(&id).into())?;
-    let choice = chat_completion_with_tools(llm, &input).await?;
-
-    match choice.finish_reason {
-        llm_sdk::FinishReason::Stop => {
-            let output = choice
-                .message
-                .content
-                .ok_or_else(|| anyhow!("expect content but no content available"))?;
+    event_sender.send(ChatReplySkeletonEvent::new(id, &config.assistant).into())?;
+    let mut blocks = 0;
+    for round in 0..MAX_TOOL_ITERATIONS {
+        // the last round offers no tools, so the model has to wrap up in words
+        let tools = if round + 1 < MAX_TOOL_ITERATIONS {
+            all_tools(config)
+        } else {
+            vec![]
+        };
+        let mut messages = history.clone();
+        messages.extend(turn.messages(&config.user.name));
+        let choice =
+            chat_completion_with_tools(state, event_sender, device_id, &messages, tools).await?;
+
+        if choice.finish_reason != FinishReason::ToolCalls {
+            let output = choice.message.content.ok_or_else(|| {
+                AppError::Upstream(anyhow!("expect content but no content available"))
+            })?;
             info!("> output {}", &output);
 
-            // 回复内容转成语音
-            event_sender.send(in_speech())?;
-            let speech_ret = SpeechResult::new_text_only(&output);
-            event_sender.send(ChatReplyEvent::new(&id, speech_ret).into())?;
+            let block_id = next_block(state, event_sender, id, &mut blocks)?;
+            if spoken {
+                event_sender.send(in_speech())?;
+                let speech_ret = SpeechResult::new_text_only(&output);
+                event_sender.send(ChatReplyEvent::new(&block_id, speech_ret).into())?;
+            }
+            finish_text(
+                state,
+                event_sender,
+                device_id,
+                id,
+                &block_id,
+                &output,
+                spoken,
+            )
+            .await?;
+            turn = turn.with_reply(output);
+            break;
+        }
+
+        // every call gets its own reply block, and they all run at once
+        let calls = choice.message.tool_calls;
+        let block_ids = calls
+            .iter()
+            .map(|_| next_block(state, event_sender, id, &mut blocks))
+            .collect::<Result<Vec<_>>>()?;
+        let history = &history;
+        let outputs = try_join_all(calls.iter().zip(&block_ids).map(
+            |(call, block_id)| async move {
+                let (tool, output) =
+                    run_tool(state, event_sender, device_id, id, block_id, history, call).await?;
+                if matches!(tool, AssistantTool::Answer) {
+                    if spoken {
+                        let speech_ret = SpeechResult::new_text_only(&output);
+                        event_sender.send(ChatReplyEvent::new(block_id, speech_ret).into())?;
+                        event_sender.send(in_speech())?;
+                    }
+                    finish_text(
+                        state,
+                        event_sender,
+                        device_id,
+                        id,
+                        block_id,
+                        &output,
+                        spoken,
+                    )
+                    .await?;
+                }
+                Ok::<_, anyhow::Error>((tool, output))
+            },
+        ))
+        .await?;
+
+        // `answer` already talked to the user, anything else is summarized next round
+        let mut answered = true;
+        for (call, (tool, output)) in calls.into_iter().zip(outputs) {
+            answered &= matches!(tool, AssistantTool::Answer);
+            turn = turn.with_tool_call(call, output);
+        }
+        if answered {
+            break;
+        }
+    }
+
+    state.memory.push(device_id, turn);
 
-            let speech_ret = speech(llm, device_id, &output).await?;
+    Ok(())
+}
+
+/// run a single tool call in the reply block `block_id`, returns what it produced
+async fn run_tool(
+    state: &AppState,
+    event_sender: &EventChannel,
+    device_id: &str,
+    id: &str,
+    block_id: &str,
+    history: &[ChatCompletionMessage],
+    tool_call: &ToolCall,
+) -> Result<(AssistantTool, String)> {
+    info!("tool call name {:?}", tool_call.function.name);
+    let tool = tool_call
+        .function
+        .name
+        .parse()
+        .unwrap_or(AssistantTool::Answer);
+    let arguments = &tool_call.function.arguments;
+    let output = match tool {
+        AssistantTool::DrawImage => {
+            let mut args: DrawImageArgs = tool_args(arguments)?;
+            // held until the images are saved, so parallel calls can't overdraw the quota
+            let reservation = state
+                .image_quota
+                .reserve(&state.storage, device_id, args.count.clamp(1, MAX_IMAGES))
+                .await?;
+            args.count = reservation.count();
+
+            event_sender.send(in_draw_image())?;
+            let ret = DrawImageResult::new(vec![], &args.prompt);
+            event_sender.send(ChatReplyEvent::new(block_id, ret).into())?;
+
+            let ret = draw_image(state, event_sender, device_id, id, args).await?;
+            drop(reservation);
             event_sender.send(complete())?;
-            event_sender.send(ChatReplyEvent::new(&id, speech_ret).into())?;
+            let event = ChatReplyEvent::new(block_id, ret.clone());
+            reply(state, event_sender, device_id, event).await?;
+
+            ret.prompt
         }
-        llm_sdk::FinishReason::ToolCalls => {
-            let tool_call = &choice.message.tool_calls[0].function;
-            println!("tool call name {:?}", tool_call.name);
-            let tool = tool_call.name.parse().unwrap_or(AssistantTool::Answer);
-            match tool {
-                AssistantTool::DrawImage => {
-                    let args: DrawImageArgs = serde_json::from_str(&tool_call.arguments)?;
-
-                    event_sender.send(in_draw_image())?;
-                    let ret = DrawImageResult::new("", &args.prompt);
-                    event_sender.send(ChatReplyEvent::new(&id, ret).into())?;
-
-                    let ret = draw_image(llm, device_id, args).await?;
-                    event_sender.send(complete())?;
-                    event_sender.send(ChatReplyEvent::new(&id, ret).into())?;
-                }
-                AssistantTool::WriteCode => {
-                    event_sender.send(in_write_code())?;
-                    let ret = write_code(llm, serde_json::from_str(&tool_call.arguments).unwrap())
-                        .await?;
+        AssistantTool::WriteCode => {
+            event_sender.send(in_write_code())?;
+            let args = tool_args(arguments)?;
+            let md = write_code(state, event_sender, device_id, block_id, history, args).await?;
 
-                    event_sender.send(complete())?;
-                    event_sender.send(ChatReplyEvent::new(&id, ret).into())?;
-                }
-                AssistantTool::Answer => {
-                    event_sender.send(in_chat_completion())?;
-                    let output =
-                        answer(llm, serde_json::from_str(&tool_call.arguments).unwrap()).await?;
-
-                    event_sender.send(complete())?;
-                    let speech_ret = SpeechResult::new_text_only(&output);
-                    event_sender.send(ChatReplyEvent::new(&id, speech_ret).into())?;
-
-                    // 回复内容转成语音
-                    event_sender.send(in_speech())?;
-                    let ret = speech(llm, device_id, &output).await?;
-                    event_sender.send(complete())?;
-                    event_sender.send(ChatReplyEvent::new(&id, ret).into())?;
-                }
-            }
+            event_sender.send(complete())?;
+            let ret = WriteCodeResult::new(md2html(&md, &state.config.markdown.theme));
+            let event = ChatReplyEvent::new(block_id, ret);
+            reply(state, event_sender, device_id, event).await?;
+
+            md
+        }
+        AssistantTool::Answer => {
+            event_sender.send(in_chat_completion())?;
+            let args = tool_args(arguments)?;
+            let output = answer(state, event_sender, device_id, block_id, history, args).await?;
+
+            event_sender.send(complete())?;
+
+            output
         }
-        _ => {}
+    };
+
+    Ok((tool, output))
+}
+
+/// arguments of a tool call, malformed ones are the model's fault
+fn tool_args<T: DeserializeOwned>(arguments: &str) -> Result<T> {
+    serde_json::from_str(arguments).map_err(|err| {
+        AppError::Upstream(anyhow!("invalid tool arguments {}: {}", arguments, err)).into()
+    })
+}
+
+/// id of the next reply block, every block after the first gets its own skeleton
+fn next_block(
+    state: &AppState,
+    event_sender: &EventChannel,
+    id: &str,
+    blocks: &mut usize,
+) -> Result<String> {
+    let block_id = match *blocks {
+        0 => id.to_string(),
+        n => {
+            let block_id = format!("{}-{}", id, n);
+            let skeleton = ChatReplySkeletonEvent::new(&block_id, &state.config.assistant);
+            event_sender.send(skeleton.into())?;
+            block_id
+        }
+    };
+    *blocks += 1;
+
+    Ok(block_id)
+}
+
+/// persist the final text reply, turned into speech first if `spoken`
+async fn finish_text(
+    state: &AppState,
+    event_sender: &EventChannel,
+    device_id: &str,
+    id: &str,
+    block_id: &str,
+    text: &str,
+    spoken: bool,
+) -> Result<()> {
+    if !spoken {
+        let event = ChatReplyEvent::new(block_id, SpeechResult::new_text_only(text));
+        return reply(state, event_sender, device_id, event).await;
     }
 
+    // 回复内容转成语音
+    let (speech_ret, paths) = speech(state, event_sender, device_id, block_id, text).await?;
+    for path in paths {
+        state
+            .storage
+            .save_asset(device_id, id, AssetKind::Audio, path)
+            .await?;
+    }
+    event_sender.send(complete())?;
+    let event = ChatReplyEvent::new(block_id, speech_ret);
+    reply(state, event_sender, device_id, event).await
+}
+
+/// persist a final reply and push it to the browser
+async fn reply(
+    state: &AppState,
+    event_sender: &EventChannel,
+    device_id: &str,
+    event: ChatReplyEvent,
+) -> Result<()> {
+    state.storage.save_reply(device_id, &event).await?;
+    event_sender.send(event.into())?;
+
     Ok(())
 }
 
-/// reply question, answer
-async fn chat_completion(
-    llm: &LlmSdk,
+/// stream a completion, pushing the partial output as `reply` updates
+async fn stream_reply<T, F>(
+    state: &AppState,
+    event_sender: &EventChannel,
+    step: AssistantStep,
+    device_id: &str,
+    id: &str,
     messages: Vec<ChatCompletionMessage>,
-) -> anyhow::Result<String> {
-    let req = ChatCompletionRequest::new(messages);
-    let mut res = llm.chat_completion(req).await?;
-    let text = res
-        .choices
-        .pop()
-        .ok_or_else(|| anyhow!("expect at least one choice"))?
-        .message
-        .content
-        .ok_or_else(|| anyhow!("expect content but no content available"))?;
+    render: F,
+) -> anyhow::Result<String>
+where
+    T: Into<ChatReplyData>,
+    F: Fn(&str) -> T,
+{
+    let timeout = state.timeouts.completion;
+    let render = &render;
+    let prompt_tokens = estimate_prompt_tokens(&messages);
+    // a retry starts the reply over, the next update replaces what was already shown
+    with_fallbacks(state, event_sender, step, |model| {
+        let req = completion_request(model, messages.clone());
+        async move {
+            let stream = timed(timeout, state.llm.chat_completion_stream(req?)).await?;
+            let mut stream = pin!(stream);
+            let mut output = String::new();
+            let mut last_sent = Instant::now();
+            while let Some(delta) =
+                timed(timeout, async { stream.next().await.transpose() }).await?
+            {
+                output.push_str(&delta);
+                if last_sent.elapsed() >= STREAM_INTERVAL {
+                    event_sender.send(ChatReplyEvent::new(id, render(&output)).into())?;
+                    last_sent = Instant::now();
+                }
+            }
 
-    Ok(text)
+            // streamed completions don't report their usage, it's estimated instead
+            let usage = Usage::tokens(prompt_tokens, estimate_tokens(&output) as u64);
+            meter(state, device_id, &model_name(model), usage).await;
+            Ok(output)
+        }
+    })
+    .await
 }
 
 /// chat tools prompt
 async fn chat_completion_with_tools(
-    llm: &LlmSdk,
-    prompt: &str,
+    state: &AppState,
+    event_sender: &EventChannel,
+    device_id: &str,
+    messages: &[ChatCompletionMessage],
+    tools: Vec<Tool>,
 ) -> anyhow::Result<ChatCompletionChoice> {
-    let req = tool_completion_request(prompt, "zheng");
-    let mut res = llm.chat_completion(req).await?;
+    let mut res = with_fallbacks(state, event_sender, AssistantStep::Thinking, |model| {
+        let req = tool_completion_request(&state.config, model, messages, tools.clone());
+        async move {
+            let res = timed(state.timeouts.thinking, state.llm.chat_completion(req?)).await?;
+            let usage = Usage::tokens(
+                res.usage.prompt_tokens as u64,
+                res.usage.completion_tokens as u64,
+            );
+            meter(state, device_id, &model_name(model), usage).await;
+            Ok(res)
+        }
+    })
+    .await?;
 
     let choice = res
         .choices
         .pop()
-        .ok_or_else(|| anyhow!("expect at least one choice"));
+        .ok_or_else(|| AppError::Upstream(anyhow!("expect at least one choice")))?;
+
+    Ok(choice)
+}
+
+/// `f` with the chat model, retried, then with each fallback model in turn until one answers
+async fn with_fallbacks<T, F, Fut>(
+    state: &AppState,
+    event_sender: &EventChannel,
+    step: AssistantStep,
+    mut f: F,
+) -> Result<T>
+where
+    F: FnMut(ChatCompleteModel) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let models = state.config.models.chat_models();
+    for pair in models.windows(2) {
+        let (model, next) = (pair[0], pair[1]);
+        match retry(
+            &state.backoff,
+            || f(model),
+            retried(event_sender, step.clone()),
+        )
+        .await
+        {
+            Err(err) if is_retryable(&err) => {
+                let next = model_name(next);
+                warn!(
+                    "{} gave up on {:?}, falling back to {}: {:#}",
+                    step, model, next, err
+                );
+                event_sender.send(
+                    SignalEvent::Retrying(RetryAttempt::with_model(step.clone(), next)).into(),
+                )?;
+            }
+            ret => return ret,
+        }
+    }
+
+    let last = models[models.len() - 1];
+    retry(&state.backoff, || f(last), retried(event_sender, step)).await
+}
+
+/// logs and signals every retry of `step`
+fn retried(
+    event_sender: &EventChannel,
+    step: AssistantStep,
+) -> impl FnMut(u32, &anyhow::Error) + '_ {
+    move |attempt, err| {
+        warn!("{} failed, retrying: {:#}", step, err);
+        let signal = SignalEvent::Retrying(RetryAttempt::new(step.clone(), attempt));
+        let _ = event_sender.send(signal.into());
+    }
+}
+
+/// add to what the device consumed, losing track of it never fails the request
+async fn meter(state: &AppState, device_id: &str, model: &str, usage: Usage) {
+    let cost = usage.cost(&state.config.pricing.get(model));
+    if let Err(err) = state
+        .storage
+        .record_usage(device_id, model, usage, cost)
+        .await
+    {
+        warn!(
+            "usage of {} by {} not recorded: {:#}",
+            model, device_id, err
+        );
+    }
+}
 
-    choice
+// the text of every message, the json around it isn't billed
+fn estimate_prompt_tokens(messages: &[ChatCompletionMessage]) -> u64 {
+    let messages = serde_json::to_value(messages).unwrap_or_default();
+    messages
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|v| v["content"].as_str())
+        .map(|v| estimate_tokens(v) as u64)
+        .sum()
 }
 
 /// speech convert to word
-async fn transcript(llm: &LlmSdk, audio_buffer: Vec<u8>) -> anyhow::Result<String> {
+async fn transcript(
+    state: &AppState,
+    event_sender: &EventChannel,
+    device_id: &str,
+    audio_buffer: Vec<u8>,
+) -> anyhow::Result<String> {
+    let config = &state.config;
     let req = WhisperRequestBuilder::default()
         .file(audio_buffer)
-        .prompt("If audio language is Chinese, please use simplified chinese")
+        .model(config.models.whisper)
+        .prompt(&config.prompts.transcription)
+        .response_format(WhisperResponseFormat::VerboseJson)
         .request_type(WhisperRequestType::Transcription)
         .build()?;
-    let res = llm.whisper(req).await?;
+    let res = retry(
+        &state.backoff,
+        || timed(state.timeouts.transcription, state.llm.whisper(req.clone())),
+        retried(event_sender, AssistantStep::Transcription),
+    )
+    .await?;
+    let model = config.models.whisper.to_string();
+    meter(state, device_id, &model, Usage::audio(res.seconds)).await;
 
     Ok(res.text)
 }
 
 // word convert to speech
-async fn speech(llm: &LlmSdk, device_id: &str, text: &str) -> anyhow::Result<SpeechResult> {
-    let req = SpeechRequest::new(text);
-    let audio_stream = llm.speech(req).await?;
+/// speak `text` sentence by sentence, the segments are synthesized in parallel and kept in order
+///
+/// playback starts with the first segment, every later one extends the playlist of `block_id`
+async fn speech(
+    state: &AppState,
+    event_sender: &EventChannel,
+    device_id: &str,
+    block_id: &str,
+    text: &str,
+) -> anyhow::Result<(SpeechResult, Vec<PathBuf>)> {
+    let sentences = split_sentences(text);
+    let total = sentences.len();
+    let mut segments = pin!(stream::iter(sentences)
+        .map(|v| speech_segment(state, event_sender, device_id, v))
+        .buffered(SPEECH_PARALLELISM));
+
+    let mut urls = Vec::with_capacity(total);
+    let mut paths = Vec::with_capacity(total);
+    while let Some((url, path)) = segments.try_next().await? {
+        urls.push(url);
+        paths.push(path);
+        // the complete playlist is the final reply, sent by the caller
+        if urls.len() < total {
+            let partial = SpeechResult::with_playlist(text, urls.clone());
+            event_sender.send(ChatReplyEvent::new(block_id, partial).into())?;
+        }
+    }
+
+    Ok((SpeechResult::with_playlist(text, urls), paths))
+}
+
+async fn speech_segment(
+    state: &AppState,
+    event_sender: &EventChannel,
+    device_id: &str,
+    text: String,
+) -> anyhow::Result<(String, PathBuf)> {
+    let config = &state.config;
+    let chars = text.chars().count() as u64;
+    let req = SpeechRequestBuilder::default()
+        .input(text)
+        .model(config.models.speech.into())
+        .voice(config.speech.voice.into())
+        .speed(config.speech.speed)
+        .build()?;
+    // only what is synthesized is billed, cache hits are free
+    let synthesize = || async {
+        let audio = retry(
+            &state.backoff,
+            || timed(state.timeouts.speech, state.llm.speech(req.clone())),
+            retried(event_sender, AssistantStep::Speech),
+        )
+        .await?;
+        let model = model_name(config.models.speech);
+        meter(state, device_id, &model, Usage::speech(chars)).await;
+        Ok::<_, anyhow::Error>(audio)
+    };
+
+    // repeated phrases are served from the cache instead of being synthesized again
+    let cache = &state.speech_cache;
+    if cache.is_enabled() {
+        let key = SpeechCache::key(&req)?;
+        if let Some(path) = cache.get(&key) {
+            return Ok((speech_url(&key), path));
+        }
+        let audio = synthesize().await?;
+        let path = cache.put(&state.storage, &key, &audio).await?;
+        return Ok((speech_url(&key), path));
+    }
+
+    let audio_stream = synthesize().await?;
     let uuid = Uuid::new_v4().to_string();
-    let path = audio_path(&device_id, &uuid);
+    let path = state.assets.audio_path(device_id, &uuid);
     if let Some(parent) = path.parent() {
         if !parent.exists() {
-            fs::create_dir_all(path.parent().unwrap()).await?;
+            fs::create_dir_all(parent).await?;
         }
     }
-    fs::write(path, audio_stream).await?;
+    fs::write(&path, audio_stream).await?;
 
-    Ok(SpeechResult::new(text, audio_url(device_id, &uuid)))
+    Ok((audio_url(device_id, &uuid), path))
 }
 
-/// coding prompt
-async fn answer(llm: &LlmSdk, args: AnswerCodeArgs) -> anyhow::Result<String> {
-    let messages = vec![
-        ChatCompletionMessage::new_system("I can help answer anything you'r like to chat.", "Q"),
-        ChatCompletionMessage::new_user(args.prompt, "zheng"),
-    ];
-
-    chat_completion(llm, messages).await
+/// reply prompt, streams the answer as plain text
+async fn answer(
+    state: &AppState,
+    event_sender: &EventChannel,
+    device_id: &str,
+    id: &str,
+    history: &[ChatCompletionMessage],
+    args: AnswerCodeArgs,
+) -> anyhow::Result<String> {
+    let config = &state.config;
+    let system = ChatCompletionMessage::new_system(&config.prompts.answer, &config.assistant.name);
+    let messages = with_history(system, history, args.prompt, &config.user.name);
+
+    stream_reply(
+        state,
+        event_sender,
+        AssistantStep::ChatCompletion,
+        device_id,
+        id,
+        messages,
+        |v| SpeechResult::new_text_only(v),
+    )
+    .await
 }
 
-/// coding prompt
-async fn write_code(llm: &LlmSdk, args: WriteCodeArgs) -> anyhow::Result<WriteCodeResult> {
-    let messages = vec![
-        ChatCompletionMessage::new_system(
-            "I'm an expert on coding, I'll write code for you in markdown format based on your prompt",
-            "Q",
-        ),
-        ChatCompletionMessage::new_user(args.prompt, "zheng"),
-    ];
-
-    let md = chat_completion(llm, messages).await?;
+/// coding prompt, streams the markdown rendered as html and returns the markdown
+async fn write_code(
+    state: &AppState,
+    event_sender: &EventChannel,
+    device_id: &str,
+    id: &str,
+    history: &[ChatCompletionMessage],
+    args: WriteCodeArgs,
+) -> anyhow::Result<String> {
+    let config = &state.config;
+    let system =
+        ChatCompletionMessage::new_system(&config.prompts.write_code, &config.assistant.name);
+    let messages = with_history(system, history, args.prompt, &config.user.name);
+
+    stream_reply(
+        state,
+        event_sender,
+        AssistantStep::WriteCode,
+        device_id,
+        id,
+        messages,
+        |v| WriteCodeResult::new(md2html(v, &config.markdown.theme)),
+    )
+    .await
+}
 
-    Ok(WriteCodeResult {
-        content: md2html(&md),
-    })
+fn with_history(
+    system: ChatCompletionMessage,
+    history: &[ChatCompletionMessage],
+    prompt: String,
+    user_name: &str,
+) -> Vec<ChatCompletionMessage> {
+    let mut messages = Vec::with_capacity(history.len() + 2);
+    messages.push(system);
+    messages.extend_from_slice(history);
+    messages.push(ChatCompletionMessage::new_user(prompt, user_name));
+
+    messages
 }
 
-fn md2html(md: &str) -> String {
-    let adapter = SyntectAdapter::new(Some("Solarized (dark)"));
+fn md2html(md: &str, theme: &str) -> String {
+    let adapter = SyntectAdapter::new(Some(theme));
     let options = comrak::Options::default();
     let mut plugins = comrak::Plugins::default();
 
@@ -251,39 +802,93 @@ fn md2html(md: &str) -> String {
     markdown_to_html_with_plugins(md, &options, &plugins)
 }
 
+/// draw `args.count` images, dall-e-3 only draws one per request so they are requested at once
+///
+/// every image is saved as soon as it's drawn, a failed one only fails the call if none was
 async fn draw_image(
-    llm: &LlmSdk,
+    state: &AppState,
+    event_sender: &EventChannel,
     device_id: &str,
+    id: &str,
     args: DrawImageArgs,
 ) -> anyhow::Result<DrawImageResult> {
     let req = CreateImageRequestBuilder::default()
         .prompt(args.prompt)
+        .model(state.config.models.image.into())
+        .size(args.size.into())
+        .quality(args.quality.into())
+        .style(args.style.into())
         .response_format(ImageResponseFormat::B64Json)
-        .build()
-        .unwrap();
-    let mut res_image = llm.create_image(req).await?;
-    let image = res_image
+        .build()?;
+    let model = model_name(state.config.models.image);
+    let drawn = join_all((0..args.count).map(|_| async {
+        let res = retry(
+            &state.backoff,
+            || timed(state.timeouts.image, state.llm.create_image(req.clone())),
+            retried(event_sender, AssistantStep::DraImage),
+        )
+        .await?;
+        // paid for once drawn, whatever happens to it next
+        meter(state, device_id, &model, Usage::images(1)).await;
+        save_image(state, device_id, id, res).await
+    }))
+    .await;
+
+    let mut urls = Vec::with_capacity(drawn.len());
+    let mut revised_prompt = String::new();
+    let mut failure = None;
+    for ret in drawn {
+        match ret {
+            Ok((url, prompt)) => {
+                urls.push(url);
+                if revised_prompt.is_empty() {
+                    revised_prompt = prompt;
+                }
+            }
+            Err(err) => {
+                warn!("an image of {} failed: {:#}", id, err);
+                failure = Some(err);
+            }
+        }
+    }
+
+    match failure {
+        Some(err) if urls.is_empty() => Err(err),
+        _ => Ok(DrawImageResult::new(urls, revised_prompt)),
+    }
+}
+
+/// write a drawn image to the assets of `device_id`, returns its url and the revised prompt
+async fn save_image(
+    state: &AppState,
+    device_id: &str,
+    id: &str,
+    mut res: CreateImageResponse,
+) -> anyhow::Result<(String, String)> {
+    let image = res
         .data
         .pop()
-        .ok_or_else(|| anyhow!("expect at least one data"))?;
-    let buffer_image = STANDARD.decode(&image.b64_json.unwrap())?;
+        .ok_or_else(|| AppError::Upstream(anyhow!("expect at least one data")))?;
+    let b64_json = image
+        .b64_json
+        .ok_or_else(|| AppError::Upstream(anyhow!("expect the image as b64_json")))?;
+    let buffer_image = STANDARD.decode(b64_json)?;
 
     let uuid = Uuid::new_v4().to_string();
-    let path = image_path(&device_id, &uuid);
+    let path = state.assets.image_path(device_id, &uuid);
     if let Some(parent) = path.parent() {
-        if !parent.exists() {
-            fs::create_dir_all(path.parent().unwrap()).await?;
-        }
+        fs::create_dir_all(parent).await?;
     }
-    fs::write(path, buffer_image).await?;
+    fs::write(&path, buffer_image).await?;
+    state
+        .storage
+        .save_asset(device_id, id, AssetKind::Image, path)
+        .await?;
 
-    Ok(DrawImageResult::new(
-        image_url(device_id, &uuid),
-        image.revised_prompt,
-    ))
+    Ok((image_url(device_id, &uuid), image.revised_prompt))
 }
 
-fn in_audio_upload() -> AssistantEvent {
+pub(crate) fn in_audio_upload() -> AssistantEvent {
     SignalEvent::Processing(AssistantStep::UploadAudio).into()
 }
 
@@ -315,7 +920,24 @@ fn complete() -> AssistantEvent {
     SignalEvent::Complete.into()
 }
 
-fn error(msg: impl Into<String>) -> AssistantEvent {
+/// the signal for a request that didn't run to the end
+pub(crate) fn stopped(reason: Stopped) -> AssistantEvent {
+    match reason {
+        Stopped::Cancelled => SignalEvent::Cancelled.into(),
+        Stopped::Interrupted => SignalEvent::Interrupted.into(),
+        Stopped::Busy => error(AppError::Busy.message()),
+    }
+}
+
+/// the signal for a request that failed, limits get one of their own
+pub(crate) fn failed(err: &AppError) -> AssistantEvent {
+    match err {
+        AppError::RateLimited(reason) => SignalEvent::Limited(reason.clone()).into(),
+        err => error(err.message()),
+    }
+}
+
+pub(crate) fn error(msg: impl Into<String>) -> AssistantEvent {
     SignalEvent::Error(msg.into()).into()
 }
 
@@ -325,10 +947,10 @@ mod tests {
 
     #[test]
     fn test_error_render() {
-        let event: String = error("error").into();
+        let event = String::try_from(error("error")).unwrap();
         assert_eq!(
             event,
-            r#"\n    <p class=\"text-red-600\">  Error error </p>\n  "#
+            "\n    <p class='text-red-600'>  Error error </p>\n  "
         );
     }
 }
@@ -1,59 +1,168 @@
-use crate::{extractors::AppContext, AppState};
+use crate::{
+    error::AppError,
+    events::{EventRegistry, Replay, SequencedEvent},
+    extractors::AppContext,
+    AppState,
+};
 use askama_axum::IntoResponse;
 use axum::{
-    extract::State,
+    extract::{Query, State},
+    http::{header, HeaderMap},
     response::sse::{Event, Sse},
 };
-use dashmap::DashMap;
+use axum_extra::extract::WithRejection;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::{convert::Infallible, sync::Arc, time::Duration};
-use tokio::sync::broadcast;
-use tokio_stream::{wrappers::BroadcastStream, StreamExt as _};
-use tracing::info;
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    StreamExt as _,
+};
+use tracing::{info, warn};
 
-use super::AssistantEvent;
+use super::{AssistantEvent, JsonEvent, SignalEvent, EVENT_SCHEMA_VERSION};
 
-const MAX_EVENTS: usize = 128;
+/// how event payloads are encoded, html fragments for the page or json for other clients
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventFormat {
+    #[default]
+    Html,
+    Json,
+}
 
-pub async fn events_handler(context: AppContext, state: State<Arc<AppState>>) -> impl IntoResponse {
-    sse_handler(context, &state.events).await
+#[derive(Debug, Deserialize)]
+pub struct EventsQuery {
+    format: Option<EventFormat>,
+}
+
+impl EventFormat {
+    /// `?format=` wins, otherwise clients asking for json get json
+    pub(crate) fn negotiate(format: Option<Self>, headers: &HeaderMap) -> Self {
+        format.unwrap_or_else(|| {
+            let accept = headers
+                .get(header::ACCEPT)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default();
+            if accept.contains("application/json") {
+                Self::Json
+            } else {
+                Self::Html
+            }
+        })
+    }
+}
+
+pub async fn events_handler(
+    context: AppContext,
+    headers: HeaderMap,
+    WithRejection(Query(query), _): WithRejection<Query<EventsQuery>, AppError>,
+    state: State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let format = EventFormat::negotiate(query.format, &headers);
+
+    // browsers send back the id of the last event they saw when they reconnect
+    let last_id = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok());
+
+    sse_handler(context, last_id, format, &state.events).await
 }
 
 // SSE 服务器向客户端推消息
 async fn sse_handler(
     context: AppContext,
-    map: &DashMap<String, broadcast::Sender<AssistantEvent>>,
-) -> impl IntoResponse {
+    last_id: Option<u64>,
+    format: EventFormat,
+    registry: &EventRegistry,
+) -> Result<impl IntoResponse, AppError> {
     let device_id = &context.device_id;
-    info!("user {} connect", device_id);
+    info!("user {} connect, last event {:?}", device_id, last_id);
 
-    let device_id = &context.device_id;
-    let rx = if let Some(tx) = map.get(device_id) {
-        tx.subscribe()
-    } else {
-        let (tx, rx) = broadcast::channel(MAX_EVENTS);
-        map.insert(device_id.to_string(), tx);
+    let channel = registry.channel(device_id);
+    let (replay, rx) = channel.subscribe(last_id)?;
 
-        rx
+    let replay = match replay {
+        Replay::Events(events) => events.into_iter().map(|v| sse_event(v, format)).collect(),
+        Replay::Resync { last_id } => {
+            warn!("user {} missed too many events, resync", device_id);
+            vec![resync_event(format).id(last_id.to_string())]
+        }
     };
-
-    let stream = BroadcastStream::new(rx)
-        .filter_map(|v| v.ok())
-        .map(|v| {
-            let (event, id) = match &v {
-                AssistantEvent::Signal(_) => ("signal", "".to_string()),
-                AssistantEvent::Input(_) => ("input", "".to_string()),
-                AssistantEvent::InputSkeleton(v) => ("input_skeleton", v.id.to_string()),
-                AssistantEvent::Reply(v) => ("reply", v.id.to_string()),
-                AssistantEvent::ReplySkeleton(v) => ("reply_skeleton", v.id.to_string()),
-            };
-
-            let data: String = v.into();
-            Event::default().data(data).event(event).id(id)
-        })
+    let live = BroadcastStream::new(rx).map(move |v| match v {
+        Ok(v) => sse_event(v, format),
+        Err(BroadcastStreamRecvError::Lagged(n)) => {
+            warn!("sse stream lagged {} events, resync", n);
+            resync_event(format)
+        }
+    });
+    let stream = tokio_stream::iter(replay)
+        .chain(live)
         .map(Ok::<_, Infallible>);
 
     let keep_alive = axum::response::sse::KeepAlive::new()
         .interval(Duration::from_secs(1))
         .text("keep-alive-text");
-    Sse::new(stream).keep_alive(keep_alive)
+    Ok(Sse::new(stream).keep_alive(keep_alive))
+}
+
+fn sse_event(v: SequencedEvent, format: EventFormat) -> Event {
+    let event = match &v.event {
+        AssistantEvent::Signal(_) => "signal",
+        AssistantEvent::Input(_) => "input",
+        AssistantEvent::InputSkeleton(_) => "input_skeleton",
+        AssistantEvent::Reply(_) => "reply",
+        AssistantEvent::ReplySkeleton(_) => "reply_skeleton",
+    };
+    let sse = Event::default().event(event).id(v.id.to_string());
+    if format == EventFormat::Json {
+        return json_data(sse, &JsonEvent::new(&v.event));
+    }
+
+    // updates carry the element they replace, the event id is taken by the sequence
+    let target = match &v.event {
+        AssistantEvent::Input(e) => Some(format!("input-{}", e.id)),
+        AssistantEvent::Reply(e) => Some(format!("reply-{}", e.id)),
+        _ => None,
+    };
+
+    let data = match String::try_from(v.event) {
+        Ok(data) => data,
+        // the page is told something is missing instead of losing the whole stream
+        Err(err) => {
+            warn!("event {} can't be rendered: {}", v.id, err);
+            let signal = SignalEvent::Error("a part of the reply can't be shown".into());
+            let data = String::try_from(signal).unwrap_or_else(|err| err.to_string());
+            return sse.event("signal").data(data);
+        }
+    };
+    let data = match target {
+        Some(target) => format!("<div id=\"{}\">{}</div>", target, data),
+        None => data,
+    };
+    sse.data(data)
+}
+
+// the client reloads the chat history from the server
+fn resync_event(format: EventFormat) -> Event {
+    let sse = Event::default().event("resync");
+    match format {
+        EventFormat::Html => sse.data("resync"),
+        EventFormat::Json => json_data(
+            sse,
+            &json!({ "version": EVENT_SCHEMA_VERSION, "type": "resync" }),
+        ),
+    }
+}
+
+fn json_data(sse: Event, payload: &impl Serialize) -> Event {
+    // the event types always serialize, keep the stream going if that ever changes
+    match serde_json::to_string(payload) {
+        Ok(data) => sse.data(data),
+        Err(err) => {
+            let error = json!({ "version": EVENT_SCHEMA_VERSION, "type": "error", "data": err.to_string() });
+            sse.data(error.to_string())
+        }
+    }
 }
@@ -1,15 +1,60 @@
+use std::sync::Arc;
+
 use askama::Template;
-use axum::response::IntoResponse;
-use axum_extra::extract::{cookie::Cookie, CookieJar};
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse,
+    Json,
+};
+use axum_extra::extract::{cookie::Cookie, CookieJar, WithRejection};
+use chrono::Local;
+use serde::Deserialize;
+use serde_json::json;
+use tracing::info;
 use uuid::Uuid;
 
+use super::{ChatInputSkeletonEvent, ChatReplySkeletonEvent};
+use crate::{
+    error::AppError,
+    extractors::{is_device_id, AppContext},
+    storage::StoredEvent,
+    AppState,
+};
+
+const HISTORY_PAGE_SIZE: usize = 20;
+
 #[derive(Debug, Template)]
 #[template(path = "index.html.jinja")]
-struct IndexTemplate {}
+struct IndexTemplate {
+    title: String,
+    history: ChatHistory,
+}
+
+/// a page of past chats, oldest first, rendered with the event stream templates
+#[derive(Debug, Default, Template)]
+#[template(path = "blocks/history.html.jinja")]
+struct ChatHistory {
+    // id to pass back as `before` to load the previous page
+    before: Option<i64>,
+    chats: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryQuery {
+    before: Option<i64>,
+}
 
-pub async fn index_page(jar: CookieJar) -> impl IntoResponse {
-    let jar = match jar.get("device_id") {
-        Some(_) => jar,
+pub async fn index_page(
+    jar: CookieJar,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    // a forged id gets replaced, like a missing one
+    let device_id = jar.get("device_id").filter(|v| is_device_id(v.value()));
+    let (jar, history) = match device_id {
+        Some(device_id) => {
+            let history = load_history(&state, device_id.value(), None).await?;
+            (jar, history)
+        }
         None => {
             let device_id = Uuid::new_v4().to_string();
             let cookie = Cookie::build("device_id", device_id)
@@ -17,9 +62,102 @@ pub async fn index_page(jar: CookieJar) -> impl IntoResponse {
                 .secure(true)
                 .permanent()
                 .finish();
-            jar.add(cookie)
+            (jar.add(cookie), ChatHistory::default())
         }
     };
 
-    (jar, IndexTemplate {})
+    let title = state.config.title.clone();
+    Ok((jar, IndexTemplate { title, history }))
+}
+
+/// forget the conversation of this device, with the files generated for it
+pub async fn delete_chats_handler(
+    context: AppContext,
+    State(state): State<Arc<AppState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let device_id = &context.device_id;
+    info!("user {} deletes the conversation", device_id);
+
+    state.tasks.cancel_all(device_id);
+    state.storage.delete_device(device_id).await?;
+    state.assets.delete_device(device_id).await?;
+    state.memory.clear(device_id);
+
+    Ok(Json(json!({"status":"deleted"})))
+}
+
+/// older chats for the "load older" button
+pub async fn history_handler(
+    context: AppContext,
+    State(state): State<Arc<AppState>>,
+    WithRejection(Query(query), _): WithRejection<Query<HistoryQuery>, AppError>,
+) -> Result<impl IntoResponse, AppError> {
+    let history = load_history(&state, &context.device_id, query.before).await?;
+
+    Ok(history)
+}
+
+async fn load_history(
+    state: &AppState,
+    device_id: &str,
+    before: Option<i64>,
+) -> anyhow::Result<ChatHistory> {
+    let config = &state.config;
+    // fetch one more than a page to know whether there is anything older
+    let mut events = state
+        .storage
+        .events(device_id, before, HISTORY_PAGE_SIZE + 1)
+        .await?;
+    let has_more = events.len() > HISTORY_PAGE_SIZE;
+    events.truncate(HISTORY_PAGE_SIZE);
+
+    let before = events.last().filter(|_| has_more).map(StoredEvent::id);
+
+    let chats = events
+        .into_iter()
+        .rev()
+        .map(|event| match event {
+            StoredEvent::Input(v) => {
+                let content = String::try_from(v.event)?;
+                let datetime = v.created_at.with_timezone(&Local);
+                ChatInputSkeletonEvent::with_content(v.request_id, &config.user, datetime, content)
+                    .render()
+            }
+            StoredEvent::Reply(v) => {
+                let content = String::try_from(v.event)?;
+                ChatReplySkeletonEvent::with_content(v.request_id, &config.assistant, content)
+                    .render()
+            }
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(ChatHistory { before, chats })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{handlers::ChatInputEvent, llm::FakeBackend, Args};
+    use clap::Parser;
+
+    #[tokio::test]
+    async fn test_load_history_pages() -> anyhow::Result<()> {
+        let args = Args::parse_from(["qbot", "--database", ":memory:"]);
+        let state = AppState::with_backend(&args, Box::<FakeBackend>::default())?;
+        for i in 0..HISTORY_PAGE_SIZE + 5 {
+            let event = ChatInputEvent::new(i.to_string(), format!("input {}", i));
+            state.storage.save_input("d", &event).await?;
+        }
+
+        let latest = load_history(&state, "d", None).await?;
+        assert_eq!(latest.chats.len(), HISTORY_PAGE_SIZE);
+        assert!(latest.chats[0].contains("input 5"));
+        assert!(latest.before.is_some());
+
+        let older = load_history(&state, "d", latest.before).await?;
+        assert_eq!(older.chats.len(), 5);
+        assert!(older.chats[0].contains("input 0"));
+        assert!(older.before.is_none());
+        Ok(())
+    }
 }
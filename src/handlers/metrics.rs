@@ -0,0 +1,24 @@
+use std::{fmt::Write, sync::Arc};
+
+use axum::{extract::State, http::header, response::IntoResponse};
+
+use crate::{assets::DiskUsage, AppState};
+
+/// disk usage in the prometheus text format, as of the last janitor sweep
+pub async fn metrics_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let usage = state.assets.usage();
+    let speech = state.speech_cache.bytes();
+
+    let mut body = String::new();
+    for (kind, v) in [("audio", usage.audio), ("image", usage.image)] {
+        let DiskUsage { files, bytes } = v;
+        let _ = writeln!(body, "qbot_asset_files{{kind=\"{}\"}} {}", kind, files);
+        let _ = writeln!(body, "qbot_asset_bytes{{kind=\"{}\"}} {}", kind, bytes);
+    }
+    let _ = writeln!(body, "qbot_asset_bytes{{kind=\"speech\"}} {}", speech);
+    let _ = writeln!(body, "qbot_asset_devices {}", usage.devices);
+    let _ = writeln!(body, "qbot_asset_swept_files_total {}", usage.swept.files);
+    let _ = writeln!(body, "qbot_asset_swept_bytes_total {}", usage.swept.bytes);
+
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body)
+}
@@ -1,20 +1,36 @@
+mod assets;
 mod assistant;
 mod chats;
 mod common;
+mod metrics;
+mod usage;
+mod ws;
 
+pub use assets::*;
 pub use assistant::*;
 pub use chats::*;
 pub use common::*;
+pub use metrics::*;
+pub use usage::*;
+pub use ws::*;
 
-use crate::tools::{DrawImageResult, WriteCodeResult};
+use crate::{
+    config::Profile,
+    tools::{DrawImageResult, WriteCodeResult},
+};
 use askama::Template;
-use chrono::Local;
+use chrono::{DateTime, Local};
 use derive_more::From;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use strum::{Display, EnumString};
 
+/// version of the json event schema, bumped on every breaking change
+pub const EVENT_SCHEMA_VERSION: u32 = 1;
+
 // 几种事件
-#[derive(Debug, Clone, From)]
+#[derive(Debug, Clone, From, Serialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
 pub enum AssistantEvent {
     Signal(SignalEvent),
     Input(ChatInputEvent),
@@ -23,6 +39,14 @@ pub enum AssistantEvent {
     ReplySkeleton(ChatReplySkeletonEvent),
 }
 
+/// an event as sent to json clients, `type` matches the sse event name
+#[derive(Debug, Serialize)]
+pub struct JsonEvent<'a> {
+    version: u32,
+    #[serde(flatten)]
+    event: &'a AssistantEvent,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Template)]
 #[template(path = "event/signal.html.jinja")]
 #[serde(tag = "type", content = "data", rename_all = "snake_case")]
@@ -31,34 +55,51 @@ pub enum SignalEvent {
     Finish(AssistantStep),
     Error(String),
     Complete,
+    Cancelled,
+    Interrupted,
+    Retrying(RetryAttempt),
+    /// turned away by a rate limit or spending cap, with the reason
+    Limited(String),
+}
+
+/// a step failed and is tried again, with another model if `model` is set
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetryAttempt {
+    step: AssistantStep,
+    attempt: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Template)]
 #[template(path = "event/chat_input.html.jinja")]
 pub struct ChatInputEvent {
-    id: String,
-    content: String,
+    pub(crate) id: String,
+    pub(crate) content: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Template)]
 #[template(path = "event/chat_input_skeleton.html.jinja")]
-struct ChatInputSkeletonEvent {
+pub struct ChatInputSkeletonEvent {
     id: String,
     datetime: String,
     avatar: String,
     name: String,
+    // rendered input, empty while waiting for the transcription
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    content: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Template)]
 #[template(path = "event/chat_reply.html.jinja")]
-struct ChatReplyEvent {
-    id: String,
+pub struct ChatReplyEvent {
+    pub(crate) id: String,
     data: ChatReplyData,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, From)]
 #[serde(tag = "type", content = "data", rename_all = "snake_case")]
-enum ChatReplyData {
+pub(crate) enum ChatReplyData {
     Speech(SpeechResult),
     Image(DrawImageResult),
     Markdown(WriteCodeResult),
@@ -66,17 +107,27 @@ enum ChatReplyData {
 
 #[derive(Debug, Clone, Serialize, Deserialize, Template)]
 #[template(path = "event/chat_reply_skeleton.html.jinja")]
-struct ChatReplySkeletonEvent {
+pub struct ChatReplySkeletonEvent {
     id: String,
     avatar: String,
     name: String,
+    // rendered reply, empty while Q is still working on it
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    content: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Template)]
 #[template(path = "blocks/speech.html.jinja")]
 pub struct SpeechResult {
     text: String,
+    // the first segment, what clients without playlist support play
     url: String,
+    // every segment of the reply, played one after another
+    #[serde(default)]
+    playlist: Vec<String>,
+    // only live replies play by themselves, stored ones come back without it
+    #[serde(skip)]
+    autoplay: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, EnumString, Display)]
@@ -92,8 +143,44 @@ pub enum AssistantStep {
     WriteCode,
 }
 
+impl RetryAttempt {
+    pub(crate) fn new(step: AssistantStep, attempt: u32) -> Self {
+        Self {
+            step,
+            attempt,
+            model: None,
+        }
+    }
+
+    pub(crate) fn with_model(step: AssistantStep, model: impl Into<String>) -> Self {
+        Self {
+            step,
+            attempt: 1,
+            model: Some(model.into()),
+        }
+    }
+}
+
+impl fmt::Display for RetryAttempt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.model {
+            Some(model) => write!(f, "{} with {}", self.step, model),
+            None => write!(f, "{}, attempt {}", self.step, self.attempt + 1),
+        }
+    }
+}
+
+impl<'a> JsonEvent<'a> {
+    pub fn new(event: &'a AssistantEvent) -> Self {
+        Self {
+            version: EVENT_SCHEMA_VERSION,
+            event,
+        }
+    }
+}
+
 impl ChatInputEvent {
-    fn new(id: impl Into<String>, message: impl Into<String>) -> Self {
+    pub(crate) fn new(id: impl Into<String>, message: impl Into<String>) -> Self {
         Self {
             id: id.into(),
             content: message.into(),
@@ -102,18 +189,28 @@ impl ChatInputEvent {
 }
 
 impl ChatInputSkeletonEvent {
-    fn new(id: impl Into<String>) -> Self {
+    fn new(id: impl Into<String>, user: &Profile) -> Self {
+        Self::with_content(id, user, Local::now(), "")
+    }
+
+    fn with_content(
+        id: impl Into<String>,
+        user: &Profile,
+        datetime: DateTime<Local>,
+        content: impl Into<String>,
+    ) -> Self {
         Self {
             id: id.into(),
-            datetime: Local::now().format("%d/%m/%Y %H:%M%S").to_string(),
-            avatar: "https://i.pravatar.cc/300".to_string(),
-            name: "userName".to_string(),
+            datetime: datetime.format("%d/%m/%Y %H:%M%S").to_string(),
+            avatar: user.avatar.clone(),
+            name: user.name.clone(),
+            content: content.into(),
         }
     }
 }
 
 impl ChatReplyEvent {
-    fn new(id: impl Into<String>, data: impl Into<ChatReplyData>) -> Self {
+    pub(crate) fn new(id: impl Into<String>, data: impl Into<ChatReplyData>) -> Self {
         Self {
             id: id.into(),
             data: data.into(),
@@ -122,70 +219,141 @@ impl ChatReplyEvent {
 }
 
 impl ChatReplySkeletonEvent {
-    fn new(id: impl Into<String>) -> Self {
+    fn new(id: impl Into<String>, assistant: &Profile) -> Self {
+        Self::with_content(id, assistant, "")
+    }
+
+    fn with_content(
+        id: impl Into<String>,
+        assistant: &Profile,
+        content: impl Into<String>,
+    ) -> Self {
         Self {
             id: id.into(),
-            avatar: "/public/images/q-bot.png".to_string(),
-            name: "Q".to_string(),
+            avatar: assistant.avatar.clone(),
+            name: assistant.name.clone(),
+            content: content.into(),
         }
     }
 }
 
 impl SpeechResult {
-    fn new(text: impl Into<String>, url: impl Into<String>) -> Self {
+    pub(crate) fn with_playlist(text: impl Into<String>, playlist: Vec<String>) -> Self {
         Self {
             text: text.into(),
-            url: url.into(),
+            url: playlist.first().cloned().unwrap_or_default(),
+            playlist,
+            autoplay: true,
         }
     }
 
     fn new_text_only(text: impl Into<String>) -> Self {
-        Self::new(text, "".to_string())
-    }
-
-    pub fn rende(&self) -> String {
-        self.render().unwrap()
+        Self::with_playlist(text, vec![])
     }
 }
 
-impl From<AssistantEvent> for String {
-    fn from(event: AssistantEvent) -> Self {
+// rendering can fail on bad data, so events only turn into html fallibly
+impl TryFrom<AssistantEvent> for String {
+    type Error = askama::Error;
+
+    fn try_from(event: AssistantEvent) -> Result<Self, Self::Error> {
         match event {
-            AssistantEvent::Signal(v) => v.into(),
-            AssistantEvent::InputSkeleton(v) => v.into(),
-            AssistantEvent::Input(v) => v.into(),
-            AssistantEvent::ReplySkeleton(v) => v.into(),
-            AssistantEvent::Reply(v) => v.into(),
+            AssistantEvent::Signal(v) => v.try_into(),
+            AssistantEvent::InputSkeleton(v) => v.try_into(),
+            AssistantEvent::Input(v) => v.try_into(),
+            AssistantEvent::ReplySkeleton(v) => v.try_into(),
+            AssistantEvent::Reply(v) => v.try_into(),
         }
     }
 }
 
-impl From<SignalEvent> for String {
-    fn from(value: SignalEvent) -> Self {
-        value.render().unwrap()
+impl TryFrom<SignalEvent> for String {
+    type Error = askama::Error;
+
+    fn try_from(value: SignalEvent) -> Result<Self, Self::Error> {
+        value.render()
     }
 }
 
-impl From<ChatInputEvent> for String {
-    fn from(value: ChatInputEvent) -> Self {
-        value.render().unwrap()
+impl TryFrom<ChatInputEvent> for String {
+    type Error = askama::Error;
+
+    fn try_from(value: ChatInputEvent) -> Result<Self, Self::Error> {
+        value.render()
     }
 }
 
-impl From<ChatInputSkeletonEvent> for String {
-    fn from(value: ChatInputSkeletonEvent) -> Self {
-        value.render().unwrap()
+impl TryFrom<ChatInputSkeletonEvent> for String {
+    type Error = askama::Error;
+
+    fn try_from(value: ChatInputSkeletonEvent) -> Result<Self, Self::Error> {
+        value.render()
+    }
+}
+
+impl TryFrom<ChatReplyEvent> for String {
+    type Error = askama::Error;
+
+    fn try_from(value: ChatReplyEvent) -> Result<Self, Self::Error> {
+        value.render()
     }
 }
 
-impl From<ChatReplyEvent> for String {
-    fn from(value: ChatReplyEvent) -> Self {
-        value.render().unwrap()
+impl TryFrom<ChatReplySkeletonEvent> for String {
+    type Error = askama::Error;
+
+    fn try_from(value: ChatReplySkeletonEvent) -> Result<Self, Self::Error> {
+        value.render()
     }
 }
 
-impl From<ChatReplySkeletonEvent> for String {
-    fn from(value: ChatReplySkeletonEvent) -> Self {
-        value.render().unwrap()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, to_value};
+
+    // json clients depend on these shapes, changing them means bumping the version
+    #[test]
+    fn test_json_event_schema() {
+        let signal: AssistantEvent = SignalEvent::Processing(AssistantStep::Speech).into();
+        assert_eq!(
+            to_value(JsonEvent::new(&signal)).unwrap(),
+            json!({ "version": 1, "type": "signal", "data": { "type": "processing", "data": "speech" } })
+        );
+
+        let input: AssistantEvent = ChatInputEvent::new("r1", "hello").into();
+        assert_eq!(
+            to_value(JsonEvent::new(&input)).unwrap(),
+            json!({ "version": 1, "type": "input", "data": { "id": "r1", "content": "hello" } })
+        );
+
+        let reply: AssistantEvent = ChatReplyEvent::new(
+            "r1",
+            SpeechResult::with_playlist("hi", vec!["/a.mp3".into()]),
+        )
+        .into();
+        assert_eq!(
+            to_value(JsonEvent::new(&reply)).unwrap(),
+            json!({
+                "version": 1,
+                "type": "reply",
+                "data": {
+                    "id": "r1",
+                    "data": {
+                        "type": "speech",
+                        "data": { "text": "hi", "url": "/a.mp3", "playlist": ["/a.mp3"] },
+                    },
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn test_only_live_speech_autoplays() {
+        let live = SpeechResult::with_playlist("hi", vec!["/a.mp3".into()]);
+        assert!(live.render().unwrap().contains("autoplay"));
+
+        let stored: SpeechResult = serde_json::from_value(to_value(&live).unwrap()).unwrap();
+        assert!(!stored.render().unwrap().contains("autoplay"));
     }
 }
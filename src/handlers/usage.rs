@@ -0,0 +1,55 @@
+use std::sync::Arc;
+
+use askama::Template;
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_extra::extract::WithRejection;
+use chrono::{Duration, Utc};
+use serde::Deserialize;
+
+use super::EventFormat;
+use crate::{error::AppError, usage::UsageReport, AppState};
+
+// the most days a report goes back
+const MAX_DAYS: i64 = 366;
+
+#[derive(Debug, Deserialize)]
+pub struct UsageQuery {
+    /// days to report on, today included
+    #[serde(default = "default_days")]
+    days: i64,
+    format: Option<EventFormat>,
+}
+
+#[derive(Debug, Template)]
+#[template(path = "usage.html.jinja")]
+struct UsageTemplate {
+    report: UsageReport,
+}
+
+/// what every device consumed lately and what it cost, as a page or as json
+pub async fn usage_handler(
+    headers: HeaderMap,
+    WithRejection(Query(query), _): WithRejection<Query<UsageQuery>, AppError>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    let days = query.days.clamp(1, MAX_DAYS);
+    let since = (Utc::now() - Duration::days(days - 1))
+        .format("%Y-%m-%d")
+        .to_string();
+    let records = state.storage.usage(&since).await?;
+    let report = UsageReport::new(since, records);
+
+    Ok(match EventFormat::negotiate(query.format, &headers) {
+        EventFormat::Json => Json(report).into_response(),
+        EventFormat::Html => UsageTemplate { report }.into_response(),
+    })
+}
+
+fn default_days() -> i64 {
+    7
+}
@@ -0,0 +1,218 @@
+use std::{mem, sync::Arc};
+
+use axum::{
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    response::IntoResponse,
+};
+use futures::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::oneshot;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use super::{
+    assistant::{assist_text, assist_voice, error, failed, in_audio_upload, run_admitted, stopped},
+    JsonEvent, EVENT_SCHEMA_VERSION, MAX_UPLOAD_BYTES,
+};
+use crate::{
+    error::AppError,
+    events::{EventChannel, SequencedEvent},
+    extractors::AppContext,
+    AppState,
+};
+
+/// control messages a client sends as text frames, the audio comes in binary frames
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    /// the audio sent so far is a complete question
+    End,
+    /// a typed question
+    Text { text: String },
+    /// stop a request in flight, or all of them without an id
+    Cancel { id: Option<String> },
+    /// stop the requests in flight and drop the audio received so far
+    Interrupt,
+    /// whether replies are spoken
+    Mode { speech: bool },
+}
+
+enum Input {
+    Voice(Vec<u8>),
+    Text(String),
+}
+
+/// a voice session over a single connection, events go down as json text frames
+pub async fn ws_handler(
+    context: AppContext,
+    State(state): State<Arc<AppState>>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    ws.max_message_size(MAX_UPLOAD_BYTES)
+        .on_upgrade(move |socket| session(socket, state, context.device_id))
+}
+
+async fn session(socket: WebSocket, state: Arc<AppState>, device_id: String) {
+    info!("user {} open websocket", device_id);
+    let channel = state.events.channel(&device_id);
+    let (mut sink, mut stream) = socket.split();
+
+    let rx = match channel.subscribe(None) {
+        Ok((_, rx)) => rx,
+        Err(err) => {
+            warn!("user {} can't subscribe: {}", device_id, err);
+            return;
+        }
+    };
+    // a reason sent here goes to this connection only, as an error, then its close frame
+    let (close_tx, mut close_rx) = oneshot::channel::<(String, CloseFrame<'static>)>();
+    let forward = tokio::spawn(async move {
+        let mut events = BroadcastStream::new(rx);
+        loop {
+            let payload = tokio::select! {
+                v = events.next() => match v {
+                    Some(Ok(v)) => frame(v),
+                    Some(Err(BroadcastStreamRecvError::Lagged(n))) => {
+                        warn!("websocket lagged {} events, resync", n);
+                        json!({ "version": EVENT_SCHEMA_VERSION, "type": "resync" })
+                    }
+                    None => break,
+                },
+                close = &mut close_rx => {
+                    if let Ok((reason, frame)) = close {
+                        let _ = sink.send(Message::Text(closing(reason))).await;
+                        let _ = sink.send(Message::Close(Some(frame))).await;
+                    }
+                    break;
+                }
+            };
+            if sink.send(Message::Text(payload.to_string())).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut audio = Vec::new();
+    let mut speech = true;
+    let mut close_tx = Some(close_tx);
+    while let Some(Ok(msg)) = stream.next().await {
+        let msg = match msg {
+            Message::Binary(chunk) => {
+                if audio.is_empty() {
+                    let _ = channel.send(in_audio_upload());
+                }
+                if audio.len() + chunk.len() > MAX_UPLOAD_BYTES {
+                    warn!(
+                        "user {} sent more than {} bytes of audio",
+                        device_id, MAX_UPLOAD_BYTES
+                    );
+                    let reason = format!("audio larger than {} bytes", MAX_UPLOAD_BYTES);
+                    let frame = CloseFrame {
+                        code: close_code::SIZE,
+                        reason: "audio too large".into(),
+                    };
+                    if let Some(tx) = close_tx.take() {
+                        let _ = tx.send((reason, frame));
+                    }
+                    break;
+                }
+                audio.extend_from_slice(&chunk);
+                continue;
+            }
+            Message::Text(text) => serde_json::from_str(&text),
+            Message::Close(_) => break,
+            _ => continue,
+        };
+
+        match msg {
+            Ok(ClientMessage::End) => {
+                let input = Input::Voice(mem::take(&mut audio));
+                spawn_assist(&state, &channel, &device_id, input, speech);
+            }
+            Ok(ClientMessage::Text { text }) => {
+                spawn_assist(&state, &channel, &device_id, Input::Text(text), speech);
+            }
+            Ok(ClientMessage::Cancel { id: Some(id) }) => {
+                state.tasks.cancel(&device_id, &id);
+            }
+            Ok(ClientMessage::Cancel { id: None }) => {
+                state.tasks.cancel_all(&device_id);
+            }
+            Ok(ClientMessage::Interrupt) => {
+                audio.clear();
+                state.tasks.interrupt(&device_id);
+            }
+            Ok(ClientMessage::Mode { speech: v }) => speech = v,
+            Err(err) => {
+                let _ = channel.send(error(format!("invalid message: {}", err)));
+            }
+        }
+    }
+
+    // requests in flight still finish, their events are kept for the next connection
+    info!("user {} close websocket", device_id);
+    if close_tx.is_none() {
+        let _ = forward.await;
+    } else {
+        forward.abort();
+    }
+}
+
+fn spawn_assist(
+    state: &Arc<AppState>,
+    channel: &Arc<EventChannel>,
+    device_id: &str,
+    input: Input,
+    speech: bool,
+) {
+    let state = state.clone();
+    let channel = channel.clone();
+    let device_id = device_id.to_string();
+
+    tokio::spawn(async move {
+        let id = Uuid::new_v4().to_string();
+        let work = async {
+            match input {
+                Input::Voice(audio) => {
+                    assist_voice(&channel, &state, &device_id, &id, audio, speech).await
+                }
+                Input::Text(text) => {
+                    assist_text(&channel, &state, &device_id, &id, &text, speech).await
+                }
+            }
+        };
+        let event = match run_admitted(&state, &device_id, &id, work).await {
+            Ok(Ok(())) => return,
+            Ok(Err(err)) => {
+                let err = AppError::from(err);
+                warn!("user {} request {} failed: {:#}", device_id, id, err);
+                failed(&err)
+            }
+            Err(reason) => stopped(reason),
+        };
+        let _ = channel.send(event);
+    });
+}
+
+// the error frame a connection gets right before it's closed
+fn closing(reason: String) -> String {
+    let event = error(reason);
+    serde_json::to_value(JsonEvent::new(&event))
+        .unwrap_or_else(|err| json!({ "version": EVENT_SCHEMA_VERSION, "type": "error", "data": err.to_string() }))
+        .to_string()
+}
+
+// the json event, plus the id sse clients get in the `id` field
+fn frame(v: SequencedEvent) -> Value {
+    let mut payload = serde_json::to_value(JsonEvent::new(&v.event)).unwrap_or_else(
+        |err| json!({ "version": EVENT_SCHEMA_VERSION, "type": "error", "data": err.to_string() }),
+    );
+    payload["id"] = v.id.into();
+
+    payload
+}
@@ -1,20 +1,62 @@
+pub mod assets;
+pub mod config;
 pub mod error;
+pub mod events;
 mod extractors;
 pub mod handlers;
+pub mod limits;
+pub mod llm;
+pub mod memory;
+pub mod preflight;
+pub mod speech;
+pub mod storage;
+pub mod tasks;
 pub mod tools;
+pub mod usage;
 
-use std::path::{Path, PathBuf};
+use std::{sync::Arc, time::Duration};
 
+use anyhow::{Context, Result};
+use assets::{Assets, Retention};
+use axum::{
+    extract::DefaultBodyLimit,
+    routing::{delete, get, post},
+    Router,
+};
 use clap::Parser;
-use dashmap::DashMap;
-use handlers::AssistantEvent;
-use llm_sdk::LlmSdk;
-use tokio::sync::broadcast;
+use config::Config;
+use events::EventRegistry;
+use handlers::{
+    asset_handler, assistant_handler, assistant_text_handler, cancel_handler, delete_chats_handler,
+    events_handler, history_handler, index_page, metrics_handler, speech_asset_handler,
+    usage_handler, ws_handler, MAX_UPLOAD_BYTES,
+};
+use limits::{ImageQuota, Limits, Quota};
+use llm::{Backoff, LlmBackend, OpenAiBackend, Timeouts};
+use memory::{ConversationStore, TruncationPolicy};
+use preflight::API_KEY_VAR;
+use speech::SpeechCache;
+use storage::Storage;
+use tasks::{BargeIn, TaskRegistry};
+use tower_http::services::ServeDir;
+
+// the longest wait between two retries, however many there were
+const MAX_BACKOFF: Duration = Duration::from_secs(8);
 
 #[derive(Debug)]
 pub struct AppState {
-    pub llm: LlmSdk,
-    pub events: DashMap<String, broadcast::Sender<AssistantEvent>>,
+    pub config: Config,
+    pub llm: Box<dyn LlmBackend>,
+    pub events: EventRegistry,
+    pub memory: ConversationStore,
+    pub storage: Storage,
+    pub assets: Assets,
+    pub tasks: TaskRegistry,
+    pub speech_cache: SpeechCache,
+    pub image_quota: ImageQuota,
+    pub limits: Limits,
+    pub timeouts: Timeouts,
+    pub backoff: Backoff,
 }
 
 #[derive(Debug, Parser)]
@@ -24,37 +66,177 @@ pub struct Args {
     pub port: u16,
     #[clap(short, long, default_value = ".certs")]
     pub cert_path: String,
+    /// toml file with prompts, names, models and voices
+    #[clap(long)]
+    pub config: Option<String>,
+    /// OpenAI compatible API, e.g. a self-hosted server
+    #[clap(long, default_value = "https://api.openai.com/v1")]
+    pub llm_base_url: String,
+    /// token budget of the conversation history sent back to the model, 0 disables it
+    #[clap(long, default_value = "2048")]
+    pub history_tokens: usize,
+    #[clap(long, value_enum, default_value_t = TruncationPolicy::DropOldest)]
+    pub history_truncation: TruncationPolicy,
+    /// sqlite database keeping the chat history
+    #[clap(long, default_value = "/tmp/qbot/qbot.db")]
+    pub database: String,
+    /// seconds an event channel is kept once its device stopped listening and talking
+    #[clap(long, default_value = "600")]
+    pub event_idle_timeout: u64,
+    /// directory generated audio and images are written to and served from
+    #[clap(long, default_value = "/tmp/qbot")]
+    pub asset_dir: String,
+    /// days generated audio and images are kept, 0 keeps them forever
+    #[clap(long, default_value = "30")]
+    pub asset_max_age_days: u64,
+    /// megabytes of generated audio and images kept per device, 0 for no limit
+    #[clap(long, default_value = "512")]
+    pub asset_device_mb: u64,
+    /// megabytes of generated audio and images kept in total, 0 for no limit
+    #[clap(long, default_value = "4096")]
+    pub asset_total_mb: u64,
+    /// what a new question does while the device still has one being answered
+    #[clap(long, value_enum, default_value_t = BargeIn::Interrupt)]
+    pub barge_in: BargeIn,
+    /// megabytes of synthesized speech kept around for repeated phrases, 0 disables the cache
+    #[clap(long, default_value = "256")]
+    pub speech_cache_mb: u64,
+    /// times a failed model call is retried before giving up on the model
+    #[clap(long, default_value = "2")]
+    pub llm_retries: u32,
+    /// milliseconds before the first retry, doubled for every retry after it
+    #[clap(long, default_value = "500")]
+    pub llm_backoff_ms: u64,
+    /// seconds a transcription may take, at most 30 as llm-sdk gives up by then anyway
+    #[clap(long, default_value = "30")]
+    pub transcription_timeout: f64,
+    /// seconds the model may take to pick a tool, at most 30 as llm-sdk gives up by then anyway
+    #[clap(long, default_value = "30")]
+    pub thinking_timeout: f64,
+    /// seconds a streaming reply may go without a new delta
+    #[clap(long, default_value = "20")]
+    pub completion_timeout: f64,
+    /// seconds a speech segment may take to synthesize, at most 30 as llm-sdk gives up by then
+    #[clap(long, default_value = "30")]
+    pub speech_timeout: f64,
+    /// seconds an image may take to draw, at most 30 as llm-sdk gives up by then anyway
+    #[clap(long, default_value = "30")]
+    pub image_timeout: f64,
+    /// images a device may draw per day (UTC)
+    #[clap(long, default_value = "20")]
+    pub image_quota: usize,
+    /// requests a device may make per minute, 0 for no limit
+    #[clap(long, default_value = "20")]
+    pub device_rate_limit: u64,
+    /// requests all devices together may make per minute, 0 for no limit
+    #[clap(long, default_value = "0")]
+    pub global_rate_limit: u64,
+    /// tokens a device may use per day (UTC), 0 for no limit
+    #[clap(long, default_value = "0")]
+    pub device_daily_tokens: u64,
+    /// tokens all devices together may use per day (UTC), 0 for no limit
+    #[clap(long, default_value = "0")]
+    pub global_daily_tokens: u64,
+    /// estimated dollars a device may spend per day (UTC), 0 for no limit
+    #[clap(long, default_value = "2")]
+    pub device_daily_cost: f64,
+    /// estimated dollars all devices together may spend per day (UTC), 0 for no limit
+    #[clap(long, default_value = "0")]
+    pub global_daily_cost: f64,
 }
 
-impl Default for AppState {
-    fn default() -> Self {
-        Self {
-            llm: LlmSdk::new(
-                "https://api.openai.com/v1",
-                std::env::var("OPENAI_API_KEY").unwrap(),
-                3,
-            ),
-            events: DashMap::new(),
-        }
+impl AppState {
+    pub fn new(args: &Args) -> Result<Self> {
+        let api_key =
+            std::env::var(API_KEY_VAR).with_context(|| format!("{} is not set", API_KEY_VAR))?;
+        // retries are ours, so they can be signalled and fall back to other models
+        let llm = OpenAiBackend::new(&args.llm_base_url, api_key, 0);
+
+        Self::with_backend(args, Box::new(llm))
     }
-}
 
-pub fn audio_path(device_id: &str, name: &str) -> PathBuf {
-    Path::new("/tmp/qbot/audio")
-        .join(device_id)
-        .join(format!("{}.mp3", name))
-}
+    pub fn with_backend(args: &Args, llm: Box<dyn LlmBackend>) -> Result<Self> {
+        let assets = Assets::new(&args.asset_dir, args.retention());
 
-pub fn audio_url(device_id: &str, name: &str) -> String {
-    format!("/assets/audio/{}/{}.mp3", device_id, name)
+        Ok(Self {
+            config: Config::load(args.config.as_ref())?,
+            llm,
+            events: EventRegistry::new(Duration::from_secs(args.event_idle_timeout)),
+            memory: ConversationStore::new(args.history_tokens, args.history_truncation),
+            storage: Storage::open(&args.database)?,
+            speech_cache: SpeechCache::open(
+                assets.speech_dir(),
+                args.speech_cache_mb * 1024 * 1024,
+            )?,
+            assets,
+            tasks: TaskRegistry::new(args.barge_in),
+            image_quota: ImageQuota::new(args.image_quota),
+            limits: args.limits(),
+            timeouts: args.timeouts(),
+            backoff: Backoff {
+                retries: args.llm_retries,
+                base: Duration::from_millis(args.llm_backoff_ms),
+                max: MAX_BACKOFF,
+            },
+        })
+    }
 }
 
-pub fn image_path(device_id: &str, name: &str) -> PathBuf {
-    Path::new("/tmp/qbot/image")
-        .join(device_id)
-        .join(format!("{}.png", name))
+impl Args {
+    fn timeouts(&self) -> Timeouts {
+        Timeouts {
+            transcription: Duration::from_secs_f64(self.transcription_timeout),
+            thinking: Duration::from_secs_f64(self.thinking_timeout),
+            completion: Duration::from_secs_f64(self.completion_timeout),
+            speech: Duration::from_secs_f64(self.speech_timeout),
+            image: Duration::from_secs_f64(self.image_timeout),
+        }
+    }
+
+    fn limits(&self) -> Limits {
+        let limit = |v: u64| (v > 0).then_some(v);
+        let cost = |v: f64| (v > 0.0).then_some(v);
+
+        Limits {
+            device: Quota {
+                requests: limit(self.device_rate_limit),
+                tokens: limit(self.device_daily_tokens),
+                cost: cost(self.device_daily_cost),
+            },
+            global: Quota {
+                requests: limit(self.global_rate_limit),
+                tokens: limit(self.global_daily_tokens),
+                cost: cost(self.global_daily_cost),
+            },
+        }
+    }
+
+    fn retention(&self) -> Retention {
+        const MB: u64 = 1024 * 1024;
+        let limit = |v: u64| (v > 0).then_some(v);
+
+        Retention {
+            max_age: limit(self.asset_max_age_days).map(|v| Duration::from_secs(v * 24 * 3600)),
+            device_bytes: limit(self.asset_device_mb).map(|v| v * MB),
+            total_bytes: limit(self.asset_total_mb).map(|v| v * MB),
+        }
+    }
 }
 
-pub fn image_url(device_id: &str, name: &str) -> String {
-    format!("/assets/image/{}/{}.png", device_id, name)
+pub fn app(state: Arc<AppState>) -> Router {
+    Router::new()
+        .route("/", get(index_page))
+        .route("/chats", get(history_handler).delete(delete_chats_handler))
+        .route("/events", get(events_handler))
+        .route("/ws", get(ws_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/usage", get(usage_handler))
+        .route("/assistant", post(assistant_handler))
+        .route("/assistant/text", post(assistant_text_handler))
+        .route("/assistant/:id", delete(cancel_handler))
+        .nest_service("/public", ServeDir::new("./html-ui/public"))
+        .route("/assets/speech/:file", get(speech_asset_handler))
+        .route("/assets/:kind/:device_id/:file", get(asset_handler))
+        .layer(DefaultBodyLimit::max(MAX_UPLOAD_BYTES))
+        .with_state(state)
 }
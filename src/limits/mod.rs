@@ -0,0 +1,272 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::Duration,
+};
+
+use anyhow::{ensure, Result};
+use chrono::{DurationRound, Utc};
+
+use crate::{
+    error::AppError,
+    storage::{today, AssetKind, RequestCount, Spent, Storage},
+};
+
+/// requests are counted over the last minute
+pub const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// what a single device, and all devices together, may use before being turned away
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    pub device: Quota,
+    pub global: Quota,
+}
+
+/// every limit is optional, `None` never turns anything away
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Quota {
+    /// requests per minute
+    pub requests: Option<u64>,
+    /// tokens per day (UTC)
+    pub tokens: Option<u64>,
+    /// dollars per day (UTC), estimated from the pricing table
+    pub cost: Option<f64>,
+}
+
+/// images a device may draw per day (UTC), those still being drawn count as drawn
+#[derive(Debug)]
+pub struct ImageQuota {
+    limit: usize,
+    // images being drawn by device, until they are saved
+    drawing: Mutex<HashMap<String, usize>>,
+    // one reservation at a time, so two can't both see the same images left
+    reserving: tokio::sync::Mutex<()>,
+}
+
+/// images set aside for a drawing, given back when dropped
+#[derive(Debug)]
+pub struct ImageReservation<'a> {
+    quota: &'a ImageQuota,
+    device_id: String,
+    count: usize,
+}
+
+impl Limits {
+    /// let a request of `device_id` through, or tell why it can't be with `AppError::RateLimited`
+    ///
+    /// spending is checked first, so requests turned away for it don't use up the rate
+    pub async fn admit(&self, storage: &Storage, device_id: &str) -> Result<()> {
+        if self.device.tokens.is_some()
+            || self.device.cost.is_some()
+            || self.global.tokens.is_some()
+            || self.global.cost.is_some()
+        {
+            let spent = storage.spent(device_id, &today()).await?;
+            if let Some(reason) = self.over_budget(&spent) {
+                return Err(AppError::RateLimited(reason).into());
+            }
+        }
+
+        if self.device.requests.is_none() && self.global.requests.is_none() {
+            return Ok(());
+        }
+        let limits = *self;
+        let count = storage
+            .log_request(device_id, RATE_WINDOW, move |count| {
+                limits.too_fast(count).is_none()
+            })
+            .await?;
+        match self.too_fast(count) {
+            Some(reason) => Err(AppError::RateLimited(reason).into()),
+            None => Ok(()),
+        }
+    }
+
+    fn over_budget(&self, spent: &Spent) -> Option<String> {
+        let checks = [
+            (self.device, spent.device, "your"),
+            (self.global, spent.total, "the shared"),
+        ];
+        checks
+            .into_iter()
+            .find_map(|(quota, (tokens, cost), whose)| {
+                if let Some(v) = quota.tokens.filter(|v| tokens >= *v) {
+                    return Some(format!(
+                        "{} daily limit of {} tokens is reached, try again tomorrow",
+                        whose, v
+                    ));
+                }
+                quota.cost.filter(|v| cost >= *v).map(|v| {
+                    format!(
+                        "{} daily spending cap of ${} is reached, try again tomorrow",
+                        whose, v
+                    )
+                })
+            })
+    }
+
+    fn too_fast(&self, count: RequestCount) -> Option<String> {
+        if let Some(v) = self.device.requests.filter(|v| count.device >= *v) {
+            return Some(format!(
+                "too many requests, at most {} a minute, slow down a little",
+                v
+            ));
+        }
+        if self.global.requests.is_some_and(|v| count.total >= v) {
+            return Some("too many requests overall, try again in a minute".to_string());
+        }
+        None
+    }
+}
+
+impl ImageQuota {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            drawing: Mutex::default(),
+            reserving: tokio::sync::Mutex::default(),
+        }
+    }
+
+    /// set aside up to `count` images for `device_id`, keep the reservation until they are saved
+    pub async fn reserve(
+        &self,
+        storage: &Storage,
+        device_id: &str,
+        count: usize,
+    ) -> Result<ImageReservation<'_>> {
+        let _guard = self.reserving.lock().await;
+        let today = Utc::now().duration_trunc(chrono::Duration::days(1))?;
+        let drawn = storage
+            .count_assets(device_id, AssetKind::Image, today)
+            .await?;
+
+        let mut drawing = self.drawing.lock().unwrap_or_else(PoisonError::into_inner);
+        let reserved = drawing.entry(device_id.to_string()).or_default();
+        let left = self.limit.saturating_sub(drawn + *reserved);
+        ensure!(
+            left > 0,
+            AppError::RateLimited(format!("daily image quota of {} reached", self.limit))
+        );
+        let count = count.min(left);
+        *reserved += count;
+
+        Ok(ImageReservation {
+            quota: self,
+            device_id: device_id.to_string(),
+            count,
+        })
+    }
+}
+
+impl ImageReservation<'_> {
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
+impl Drop for ImageReservation<'_> {
+    fn drop(&mut self) {
+        let mut drawing = self
+            .quota
+            .drawing
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(reserved) = drawing.get_mut(&self.device_id) {
+            *reserved = reserved.saturating_sub(self.count);
+            if *reserved == 0 {
+                drawing.remove(&self.device_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Pricing, usage::Usage};
+
+    fn rate_limited(res: Result<()>) -> String {
+        match AppError::from(res.unwrap_err()) {
+            AppError::RateLimited(reason) => reason,
+            err => panic!("expected a rate limit, got {:?}", err),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_requests_over_the_rate_are_turned_away() -> Result<()> {
+        let storage = Storage::open_in_memory()?;
+        let limits = Limits {
+            device: Quota {
+                requests: Some(2),
+                ..Default::default()
+            },
+            global: Quota {
+                requests: Some(3),
+                ..Default::default()
+            },
+        };
+
+        limits.admit(&storage, "a").await?;
+        limits.admit(&storage, "a").await?;
+        let reason = rate_limited(limits.admit(&storage, "a").await);
+        assert!(reason.contains("at most 2 a minute"));
+
+        limits.admit(&storage, "b").await?;
+        let reason = rate_limited(limits.admit(&storage, "c").await);
+        assert!(reason.contains("overall"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_images_being_drawn_count_against_the_quota() -> Result<()> {
+        let storage = Storage::open_in_memory()?;
+        let quota = ImageQuota::new(3);
+
+        let first = quota.reserve(&storage, "a", 2).await?;
+        assert_eq!(first.count(), 2);
+        let second = quota.reserve(&storage, "a", 2).await?;
+        assert_eq!(second.count(), 1);
+        let reason = rate_limited(quota.reserve(&storage, "a", 1).await.map(|_| ()));
+        assert!(reason.contains("daily image quota of 3"));
+        // other devices have a quota of their own
+        assert_eq!(quota.reserve(&storage, "b", 4).await?.count(), 3);
+
+        // given back when the drawing failed
+        drop(second);
+        assert_eq!(quota.reserve(&storage, "a", 2).await?.count(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_spending_caps_hold_for_the_day() -> Result<()> {
+        let storage = Storage::open_in_memory()?;
+        let pricing = Pricing::default();
+        let limits = Limits {
+            device: Quota {
+                tokens: Some(1000),
+                ..Default::default()
+            },
+            global: Quota {
+                cost: Some(0.05),
+                ..Default::default()
+            },
+        };
+
+        let chat = Usage::tokens(600, 400);
+        let cost = chat.cost(&pricing.get("gpt-3.5-turbo-1106"));
+        storage
+            .record_usage("a", "gpt-3.5-turbo-1106", chat, cost)
+            .await?;
+        let reason = rate_limited(limits.admit(&storage, "a").await);
+        assert!(reason.contains("your daily limit of 1000 tokens"));
+        limits.admit(&storage, "b").await?;
+
+        let images = Usage::images(2);
+        let cost = images.cost(&pricing.get("dall-e-3"));
+        storage.record_usage("b", "dall-e-3", images, cost).await?;
+        let reason = rate_limited(limits.admit(&storage, "b").await);
+        assert!(reason.contains("shared daily spending cap of $0.05"));
+        Ok(())
+    }
+}
@@ -0,0 +1,247 @@
+use std::{collections::VecDeque, sync::Mutex, time::Duration};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::{stream, stream::BoxStream, StreamExt};
+use llm_sdk::{
+    ChatCompletionRequest, ChatCompletionResponse, CreateImageRequest, CreateImageResponse,
+    SpeechRequest, WhisperRequest,
+};
+use serde_json::{json, Value};
+
+use super::{LlmBackend, Transcript};
+use crate::error::AppError;
+
+/// how long every transcribed recording lasts
+pub const FAKE_AUDIO_SECONDS: f64 = 3.0;
+
+/// tokens every scripted completion reports to have used, prompt and completion
+pub const FAKE_TOKENS: (u32, u32) = (20, 5);
+
+/// canned bytes returned for every speech request
+pub const FAKE_SPEECH: &[u8] = b"fake mp3";
+
+/// a 1x1 transparent png returned for every image
+pub const FAKE_PNG: &[u8] = &[
+    0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52,
+    0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06, 0x00, 0x00, 0x00, 0x1f, 0x15, 0xc4,
+    0x89, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x44, 0x41, 0x54, 0x78, 0x9c, 0x63, 0x00, 0x01, 0x00, 0x00,
+    0x05, 0x00, 0x01, 0x0d, 0x0a, 0x2d, 0xb4, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44, 0xae,
+    0x42, 0x60, 0x82,
+];
+
+/// deterministic backend for tests, every call pops the next scripted answer
+#[derive(Debug, Default)]
+pub struct FakeBackend {
+    transcripts: Mutex<VecDeque<String>>,
+    completions: Mutex<VecDeque<Value>>,
+    streams: Mutex<VecDeque<Vec<String>>>,
+    requests: Mutex<Vec<Value>>,
+    speeches: Mutex<Vec<String>>,
+    latency: Duration,
+    failures: Mutex<usize>,
+    rejections: Mutex<usize>,
+    image_failures: Mutex<usize>,
+}
+
+impl FakeBackend {
+    /// every call waits this long before answering, like a slow network would
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// the next `n` calls fail, like a provider having a bad moment
+    pub fn with_failures(self, n: usize) -> Self {
+        *self.failures.lock().unwrap() += n;
+        self
+    }
+
+    /// the next `n` calls are turned down for good, like a bad api key would be
+    pub fn with_rejections(self, n: usize) -> Self {
+        *self.rejections.lock().unwrap() += n;
+        self
+    }
+
+    /// the next `n` image requests fail, whatever else is asked in between
+    pub fn with_image_failures(self, n: usize) -> Self {
+        *self.image_failures.lock().unwrap() += n;
+        self
+    }
+
+    pub fn with_transcript(self, text: impl Into<String>) -> Self {
+        self.transcripts.lock().unwrap().push_back(text.into());
+        self
+    }
+
+    /// the model answers directly, without calling a tool
+    pub fn with_reply(self, content: impl Into<String>) -> Self {
+        let message = json!({ "role": "assistant", "content": content.into() });
+        self.with_completion("stop", message)
+    }
+
+    /// the model picks `name` with the json `arguments`
+    pub fn with_tool_call(self, name: &str, arguments: Value) -> Self {
+        self.with_tool_calls([(name, arguments)])
+    }
+
+    /// the model picks several tools at once, in parallel
+    pub fn with_tool_calls<'a>(self, calls: impl IntoIterator<Item = (&'a str, Value)>) -> Self {
+        let tool_calls: Vec<_> = calls
+            .into_iter()
+            .enumerate()
+            .map(|(i, (name, arguments))| {
+                json!({
+                    "id": format!("call_{}_{}", name, i),
+                    "type": "function",
+                    "function": { "name": name, "arguments": arguments.to_string() },
+                })
+            })
+            .collect();
+        let message = json!({ "role": "assistant", "content": null, "tool_calls": tool_calls });
+        self.with_completion("tool_calls", message)
+    }
+
+    /// deltas yielded by the next streaming completion
+    pub fn with_stream<I, S>(self, deltas: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let deltas = deltas.into_iter().map(Into::into).collect();
+        self.streams.lock().unwrap().push_back(deltas);
+        self
+    }
+
+    /// text of every speech request received so far
+    pub fn speech_inputs(&self) -> Vec<String> {
+        self.speeches.lock().unwrap().clone()
+    }
+
+    /// every chat request received so far, in wire format
+    pub fn chat_requests(&self) -> Vec<Value> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    fn with_completion(self, finish_reason: &str, message: Value) -> Self {
+        let res = json!({
+            "id": "chatcmpl-fake",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "gpt-3.5-turbo-1106",
+            "system_fingerprint": "fake",
+            "choices": [{ "index": 0, "finish_reason": finish_reason, "message": message }],
+            "usage": {
+                "prompt_tokens": FAKE_TOKENS.0,
+                "completion_tokens": FAKE_TOKENS.1,
+                "total_tokens": FAKE_TOKENS.0 + FAKE_TOKENS.1,
+            },
+        });
+        self.completions.lock().unwrap().push_back(res);
+        self
+    }
+
+    fn record(&self, req: &ChatCompletionRequest) -> Result<()> {
+        self.requests
+            .lock()
+            .unwrap()
+            .push(serde_json::to_value(req)?);
+        self.fail()
+    }
+
+    fn fail(&self) -> Result<()> {
+        let mut failures = self.failures.lock().unwrap();
+        if *failures > 0 {
+            *failures -= 1;
+            return Err(AppError::Upstream(anyhow!("scripted failure")).into());
+        }
+        let mut rejections = self.rejections.lock().unwrap();
+        if *rejections > 0 {
+            *rejections -= 1;
+            return Err(AppError::Rejected(anyhow!("scripted rejection")).into());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl LlmBackend for FakeBackend {
+    async fn chat_completion(&self, req: ChatCompletionRequest) -> Result<ChatCompletionResponse> {
+        tokio::time::sleep(self.latency).await;
+        self.record(&req)?;
+        let res = self
+            .completions
+            .lock()
+            .unwrap()
+            .pop_front()
+            .ok_or_else(|| AppError::Upstream(anyhow!("no completion scripted")))?;
+
+        Ok(serde_json::from_value(res)?)
+    }
+
+    async fn chat_completion_stream(
+        &self,
+        req: ChatCompletionRequest,
+    ) -> Result<BoxStream<'static, Result<String>>> {
+        tokio::time::sleep(self.latency).await;
+        self.record(&req)?;
+        let deltas = self
+            .streams
+            .lock()
+            .unwrap()
+            .pop_front()
+            .ok_or_else(|| AppError::Upstream(anyhow!("no stream scripted")))?;
+
+        Ok(stream::iter(deltas.into_iter().map(Ok)).boxed())
+    }
+
+    async fn whisper(&self, _req: WhisperRequest) -> Result<Transcript> {
+        tokio::time::sleep(self.latency).await;
+        self.fail()?;
+        let text = self
+            .transcripts
+            .lock()
+            .unwrap()
+            .pop_front()
+            .ok_or_else(|| AppError::Upstream(anyhow!("no transcript scripted")))?;
+
+        Ok(Transcript {
+            text,
+            seconds: FAKE_AUDIO_SECONDS,
+        })
+    }
+
+    async fn speech(&self, req: SpeechRequest) -> Result<Vec<u8>> {
+        tokio::time::sleep(self.latency).await;
+        self.fail()?;
+        let input = serde_json::to_value(&req)?["input"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        self.speeches.lock().unwrap().push(input);
+        Ok(FAKE_SPEECH.to_vec())
+    }
+
+    async fn create_image(&self, req: CreateImageRequest) -> Result<CreateImageResponse> {
+        tokio::time::sleep(self.latency).await;
+        self.fail()?;
+        {
+            let mut failures = self.image_failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(AppError::Upstream(anyhow!("scripted image failure")).into());
+            }
+        }
+        let prompt = serde_json::to_value(&req)?["prompt"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        let res = json!({
+            "created": 0,
+            "data": [{ "b64_json": STANDARD.encode(FAKE_PNG), "revised_prompt": prompt }],
+        });
+
+        Ok(serde_json::from_value(res)?)
+    }
+}
@@ -0,0 +1,116 @@
+#[cfg(any(test, feature = "fake"))]
+mod fake;
+mod openai;
+mod retry;
+mod stream;
+
+#[cfg(any(test, feature = "fake"))]
+pub use fake::*;
+pub use openai::*;
+pub use retry::*;
+pub use stream::*;
+
+use std::{fmt::Debug, sync::Arc};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use llm_sdk::{
+    ChatCompletionRequest, ChatCompletionResponse, CreateImageRequest, CreateImageResponse,
+    SpeechRequest, WhisperRequest,
+};
+use serde::Deserialize;
+
+/// what was said, and how long the recording was if the provider tells
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Transcript {
+    pub text: String,
+    pub seconds: f64,
+}
+
+/// everything the assistant needs from a model provider
+#[async_trait]
+pub trait LlmBackend: Debug + Send + Sync {
+    async fn chat_completion(&self, req: ChatCompletionRequest) -> Result<ChatCompletionResponse>;
+
+    /// content deltas of the completion as they arrive
+    async fn chat_completion_stream(
+        &self,
+        req: ChatCompletionRequest,
+    ) -> Result<BoxStream<'static, Result<String>>>;
+
+    /// ask for `verbose_json` to learn the duration of the recording
+    async fn whisper(&self, req: WhisperRequest) -> Result<Transcript>;
+
+    /// encoded audio of the speech
+    async fn speech(&self, req: SpeechRequest) -> Result<Vec<u8>>;
+
+    async fn create_image(&self, req: CreateImageRequest) -> Result<CreateImageResponse>;
+}
+
+#[async_trait]
+impl<T: LlmBackend + ?Sized> LlmBackend for Arc<T> {
+    async fn chat_completion(&self, req: ChatCompletionRequest) -> Result<ChatCompletionResponse> {
+        self.as_ref().chat_completion(req).await
+    }
+
+    async fn chat_completion_stream(
+        &self,
+        req: ChatCompletionRequest,
+    ) -> Result<BoxStream<'static, Result<String>>> {
+        self.as_ref().chat_completion_stream(req).await
+    }
+
+    async fn whisper(&self, req: WhisperRequest) -> Result<Transcript> {
+        self.as_ref().whisper(req).await
+    }
+
+    async fn speech(&self, req: SpeechRequest) -> Result<Vec<u8>> {
+        self.as_ref().speech(req).await
+    }
+
+    async fn create_image(&self, req: CreateImageRequest) -> Result<CreateImageResponse> {
+        self.as_ref().create_image(req).await
+    }
+}
+
+impl Transcript {
+    /// the body of a transcription, plain text or `verbose_json`
+    pub fn parse(body: impl Into<String>) -> Self {
+        #[derive(Deserialize)]
+        struct Verbose {
+            text: String,
+            duration: f64,
+        }
+
+        let body = body.into();
+        match serde_json::from_str::<Verbose>(&body) {
+            Ok(v) => Self {
+                text: v.text,
+                seconds: v.duration,
+            },
+            Err(_) => Self {
+                text: body,
+                seconds: 0.0,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transcript_parse() {
+        let verbose = r#"{"task":"transcribe","language":"english","duration":2.5,"text":"hi"}"#;
+        assert_eq!(
+            Transcript::parse(verbose),
+            Transcript {
+                text: "hi".into(),
+                seconds: 2.5
+            }
+        );
+        assert_eq!(Transcript::parse("hi").text, "hi");
+    }
+}
@@ -0,0 +1,179 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use futures::{stream::BoxStream, StreamExt};
+use llm_sdk::{
+    ChatCompletionRequest, ChatCompletionResponse, CreateImageRequest, CreateImageResponse, LlmSdk,
+    SpeechRequest, WhisperRequest,
+};
+use reqwest::StatusCode;
+use serde_json::Value;
+
+use super::{ChatStream, LlmBackend, Transcript};
+use crate::error::AppError;
+
+/// OpenAI, or any server speaking the same API
+#[derive(Debug, Clone)]
+pub struct OpenAiBackend {
+    sdk: LlmSdk,
+    stream: ChatStream,
+}
+
+impl OpenAiBackend {
+    pub fn new(base_url: impl Into<String>, token: impl Into<String>, max_retries: u32) -> Self {
+        let base_url = base_url.into();
+        let token = token.into();
+
+        Self {
+            sdk: LlmSdk::new(&base_url, &token, max_retries),
+            stream: ChatStream::new(base_url, token),
+        }
+    }
+}
+
+#[async_trait]
+impl LlmBackend for OpenAiBackend {
+    async fn chat_completion(&self, req: ChatCompletionRequest) -> Result<ChatCompletionResponse> {
+        self.sdk.chat_completion(req).await.map_err(upstream)
+    }
+
+    async fn chat_completion_stream(
+        &self,
+        req: ChatCompletionRequest,
+    ) -> Result<BoxStream<'static, Result<String>>> {
+        let stream = self.stream.chat_completion(&req).await.map_err(upstream)?;
+        Ok(stream.map(|v| v.map_err(upstream)).boxed())
+    }
+
+    async fn whisper(&self, req: WhisperRequest) -> Result<Transcript> {
+        // llm-sdk only parses plain `json`, anything else comes back as the raw body
+        let res = self.sdk.whisper(req).await.map_err(upstream)?;
+        Ok(Transcript::parse(res.text))
+    }
+
+    async fn speech(&self, req: SpeechRequest) -> Result<Vec<u8>> {
+        Ok(self.sdk.speech(req).await.map_err(upstream)?.to_vec())
+    }
+
+    async fn create_image(&self, req: CreateImageRequest) -> Result<CreateImageResponse> {
+        self.sdk.create_image(req).await.map_err(upstream)
+    }
+}
+
+/// seconds after which llm-sdk gives up on any call, whatever our own timeouts say
+pub const SDK_TIMEOUT_SECS: f64 = 30.0;
+
+// how llm-sdk, and `ChatStream` after it, tell about a call the provider answered with an error
+const API_FAILED: &str = "API failed: ";
+
+// what the provider turns down for good, by the `type` or `code` of the error in its answer
+const REFUSALS: &[&str] = &[
+    "invalid_request_error",
+    "authentication_error",
+    "permission_error",
+    "not_found_error",
+    "insufficient_quota",
+    "invalid_api_key",
+    "context_length_exceeded",
+    "content_policy_violation",
+    "model_not_found",
+];
+
+/// whatever the provider failed with, it's its failure and not ours
+fn upstream(err: anyhow::Error) -> anyhow::Error {
+    // `ChatStream` sorted out its own already
+    if err.is::<AppError>() {
+        return err;
+    }
+    let timeout = err
+        .chain()
+        .filter_map(|v| v.downcast_ref::<reqwest::Error>())
+        .any(|v| v.is_timeout());
+    if timeout {
+        return AppError::Timeout(err).into();
+    }
+    // llm-sdk keeps the body of a failed call, but not its status
+    let refused = err.chain().any(|v| {
+        v.to_string()
+            .strip_prefix(API_FAILED)
+            .is_some_and(|body| is_refused(None, body))
+    });
+    match refused {
+        true => AppError::Rejected(err).into(),
+        false => AppError::Upstream(err).into(),
+    }
+}
+
+/// a call the provider answered with `status` and the error `body`
+pub(super) fn api_failed(status: Option<StatusCode>, body: &str) -> anyhow::Error {
+    let err = anyhow!("{}{}", API_FAILED, body);
+    match is_refused(status, body) {
+        true => AppError::Rejected(err).into(),
+        false => AppError::Upstream(err).into(),
+    }
+}
+
+// rate limits and server errors may pass, a bad key, request or prompt won't
+fn is_refused(status: Option<StatusCode>, body: &str) -> bool {
+    let body: Value = serde_json::from_str(body).unwrap_or_default();
+    let error = &body["error"];
+    let mut tags = [&error["type"], &error["code"]]
+        .into_iter()
+        .filter_map(Value::as_str);
+    // running out of credits is a 429 as well, but won't be over in a few seconds
+    if tags.clone().any(|v| v == "insufficient_quota") {
+        return true;
+    }
+    match status {
+        Some(StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS) => false,
+        Some(status) => status.is_client_error(),
+        None => tags.any(|v| REFUSALS.contains(&v)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code(err: anyhow::Error) -> &'static str {
+        AppError::from(err).code()
+    }
+
+    #[test]
+    fn test_refusals_are_told_from_failures() {
+        let body = |kind: &str, code: &str| {
+            format!(
+                r#"{{"error":{{"message":"nope","type":"{}","param":null,"code":"{}"}}}}"#,
+                kind, code
+            )
+        };
+        let sdk = |body: String| upstream(anyhow!("{}{}", API_FAILED, body));
+
+        let key = body("invalid_request_error", "invalid_api_key");
+        assert_eq!(code(sdk(key.clone())), "rejected");
+        assert_eq!(
+            code(api_failed(Some(StatusCode::UNAUTHORIZED), &key)),
+            "rejected"
+        );
+        let context = body("invalid_request_error", "context_length_exceeded");
+        assert_eq!(code(sdk(context)), "rejected");
+        let quota = body("insufficient_quota", "insufficient_quota");
+        assert_eq!(
+            code(api_failed(Some(StatusCode::TOO_MANY_REQUESTS), &quota)),
+            "rejected"
+        );
+
+        let rate = body("requests", "rate_limit_exceeded");
+        assert_eq!(code(sdk(rate.clone())), "upstream");
+        assert_eq!(
+            code(api_failed(Some(StatusCode::TOO_MANY_REQUESTS), &rate)),
+            "upstream"
+        );
+        assert_eq!(code(sdk(body("server_error", "null"))), "upstream");
+        assert_eq!(
+            code(api_failed(Some(StatusCode::BAD_GATEWAY), "<html>")),
+            "upstream"
+        );
+        assert_eq!(code(sdk("<html>".to_string())), "upstream");
+        assert_eq!(code(upstream(anyhow!("connection reset"))), "upstream");
+    }
+}
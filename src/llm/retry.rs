@@ -0,0 +1,171 @@
+use std::{future::Future, time::Duration};
+
+use anyhow::{anyhow, Result};
+use rand::Rng;
+
+use crate::error::AppError;
+
+/// how long each step may wait on the provider before giving up on the call
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub transcription: Duration,
+    pub thinking: Duration,
+    /// between two deltas of a streaming completion
+    pub completion: Duration,
+    pub speech: Duration,
+    pub image: Duration,
+}
+
+/// exponential backoff with jitter between retries
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub retries: u32,
+    pub base: Duration,
+    pub max: Duration,
+}
+
+impl Backoff {
+    /// how long to wait before retry `n`, counting from 1
+    pub fn delay(&self, n: u32) -> Duration {
+        let cap = self
+            .base
+            .saturating_mul(2u32.saturating_pow(n.saturating_sub(1)))
+            .min(self.max);
+        // half of it fixed, so retries still back off, the other half spreads them out
+        let half = cap / 2;
+        half + half.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+}
+
+/// run `f` until it succeeds, fails for good or runs out of retries;
+/// `on_retry` is told about every failure that is retried
+pub async fn retry<T, F, Fut, R>(backoff: &Backoff, mut f: F, mut on_retry: R) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+    R: FnMut(u32, &anyhow::Error),
+{
+    let mut n = 0;
+    loop {
+        match f().await {
+            Err(err) if n < backoff.retries && is_retryable(&err) => {
+                n += 1;
+                on_retry(n, &err);
+                tokio::time::sleep(backoff.delay(n)).await;
+            }
+            ret => return ret,
+        }
+    }
+}
+
+/// `fut`, failing as a timeout once `timeout` is over
+pub async fn timed<T>(timeout: Duration, fut: impl Future<Output = Result<T>>) -> Result<T> {
+    match tokio::time::timeout(timeout, fut).await {
+        Ok(ret) => ret,
+        Err(_) => Err(AppError::Timeout(anyhow!("no answer within {:?}", timeout)).into()),
+    }
+}
+
+/// provider failures may go away on their own, its refusals and anything else won't
+pub fn is_retryable(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<AppError>(),
+        Some(AppError::Upstream(_) | AppError::Timeout(_))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    const BACKOFF: Backoff = Backoff {
+        retries: 2,
+        base: Duration::from_millis(10),
+        max: Duration::from_millis(15),
+    };
+
+    #[test]
+    fn test_delay_grows_up_to_max() {
+        for _ in 0..10 {
+            let first = BACKOFF.delay(1);
+            assert!(first >= Duration::from_millis(5) && first <= Duration::from_millis(10));
+            let late = BACKOFF.delay(30);
+            assert!(late >= Duration::from_micros(7500) && late <= Duration::from_millis(15));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_until_success() {
+        let calls = AtomicU32::new(0);
+        let mut retries = vec![];
+        let ret = retry(
+            &BACKOFF,
+            || async {
+                match calls.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(AppError::Upstream(anyhow!("flaky")).into()),
+                    _ => Ok("ok"),
+                }
+            },
+            |n, _| retries.push(n),
+        )
+        .await;
+        assert_eq!(ret.unwrap(), "ok");
+        assert_eq!(retries, vec![1]);
+    }
+
+    #[tokio::test]
+    async fn test_retry_gives_up() {
+        let calls = AtomicU32::new(0);
+        let ret: Result<()> = retry(
+            &BACKOFF,
+            || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(AppError::Upstream(anyhow!("down")).into())
+            },
+            |_, _| {},
+        )
+        .await;
+        assert!(ret.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // our own errors don't get better by asking again
+        let calls = AtomicU32::new(0);
+        let ret: Result<()> = retry(
+            &BACKOFF,
+            || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(AppError::bad_input("nope").into())
+            },
+            |_, _| {},
+        )
+        .await;
+        assert!(ret.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // neither do requests the provider turned down, like a bad key
+        let calls = AtomicU32::new(0);
+        let ret: Result<()> = retry(
+            &BACKOFF,
+            || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(AppError::Rejected(anyhow!("API failed: invalid api key")).into())
+            },
+            |_, _| {},
+        )
+        .await;
+        assert!(ret.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_timed_out_is_a_timeout() {
+        let ret = timed(Duration::from_millis(10), async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            Ok(())
+        })
+        .await;
+        let err = AppError::from(ret.unwrap_err());
+        assert_eq!(err.code(), "timeout");
+    }
+}
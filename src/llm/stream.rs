@@ -0,0 +1,150 @@
+use anyhow::Result;
+use futures::{future, stream, Stream, StreamExt};
+use llm_sdk::ChatCompletionRequest;
+use serde::Deserialize;
+
+use super::openai::api_failed;
+
+/// streaming chat completion, which llm-sdk doesn't support
+#[derive(Debug, Clone)]
+pub struct ChatStream {
+    client: reqwest::Client,
+    base_url: String,
+    token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatCompletionChunk {
+    choices: Vec<ChunkChoice>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChunkChoice {
+    delta: ChunkDelta,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChunkDelta {
+    #[serde(default)]
+    content: Option<String>,
+}
+
+impl ChatStream {
+    pub fn new(base_url: impl Into<String>, token: impl Into<String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base_url: base_url.into(),
+            token: token.into(),
+        }
+    }
+
+    /// yields the content deltas of the completion as they arrive
+    pub async fn chat_completion(
+        &self,
+        req: &ChatCompletionRequest,
+    ) -> Result<impl Stream<Item = Result<String>>> {
+        // the request fields are private, flip `stream` on the wire format instead
+        let mut body = serde_json::to_value(req)?;
+        body["stream"] = true.into();
+
+        let res = self
+            .client
+            .post(format!("{}/chat/completions", self.base_url))
+            .bearer_auth(&self.token)
+            .json(&body)
+            .send()
+            .await?;
+        let status = res.status();
+        if status.is_client_error() || status.is_server_error() {
+            return Err(api_failed(Some(status), &res.text().await?));
+        }
+
+        Ok(deltas(res.bytes_stream()))
+    }
+}
+
+fn deltas<S, B, E>(bytes: S) -> impl Stream<Item = Result<String>>
+where
+    S: Stream<Item = Result<B, E>>,
+    B: AsRef<[u8]>,
+    E: Into<anyhow::Error>,
+{
+    // a chunk may end in the middle of a line, or of a multi-byte char
+    bytes
+        .scan(Vec::new(), |buf, chunk| {
+            let lines = chunk.map_err(Into::into).map(|chunk| {
+                buf.extend_from_slice(chunk.as_ref());
+                let mut lines = vec![];
+                while let Some(pos) = buf.iter().position(|b| *b == b'\n') {
+                    let line: Vec<u8> = buf.drain(..=pos).collect();
+                    lines.push(String::from_utf8_lossy(&line).trim().to_string());
+                }
+                lines
+            });
+            future::ready(Some(lines))
+        })
+        .flat_map(|lines| match lines {
+            Ok(lines) => stream::iter(lines.into_iter().map(Ok).collect::<Vec<_>>()),
+            Err(err) => stream::iter(vec![Err(err)]),
+        })
+        .filter_map(|line| future::ready(line.and_then(|v| parse_line(&v)).transpose()))
+}
+
+// `data: {...}` lines carry a chunk, everything else (comments, `[DONE]`) is skipped
+fn parse_line(line: &str) -> Result<Option<String>> {
+    let Some(data) = line.strip_prefix("data:").map(str::trim) else {
+        return Ok(None);
+    };
+    if data == "[DONE]" {
+        return Ok(None);
+    }
+
+    let chunk: ChatCompletionChunk = serde_json::from_str(data)?;
+    let content = chunk
+        .choices
+        .into_iter()
+        .filter_map(|v| v.delta.content)
+        .collect::<String>();
+
+    Ok(Some(content).filter(|v| !v.is_empty()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(content: &str) -> String {
+        format!(
+            "data: {{\"choices\":[{{\"index\":0,\"delta\":{{\"content\":\"{}\"}}}}]}}\n\n",
+            content
+        )
+    }
+
+    #[tokio::test]
+    async fn test_deltas_across_chunks() {
+        let body = format!(
+            "data: {{\"choices\":[{{\"delta\":{{\"role\":\"assistant\"}}}}]}}\n\n{}{}data: [DONE]\n\n",
+            chunk("你好"),
+            chunk(", world")
+        );
+        // split in the middle of a line and of a multi-byte char
+        let bytes = body.into_bytes();
+        let chunks = bytes
+            .chunks(7)
+            .map(|v| Ok::<_, anyhow::Error>(v.to_vec()))
+            .collect::<Vec<_>>();
+
+        let deltas: Vec<String> = deltas(stream::iter(chunks))
+            .map(|v| v.unwrap())
+            .collect()
+            .await;
+        assert_eq!(deltas, vec!["你好", ", world"]);
+    }
+
+    #[test]
+    fn test_parse_line_skips_non_data() {
+        assert!(parse_line(": keep-alive").unwrap().is_none());
+        assert!(parse_line("data: [DONE]").unwrap().is_none());
+        assert!(parse_line("data: not json").is_err());
+    }
+}
@@ -1,33 +1,58 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use anyhow::{Ok, Result};
-use axum::{
-    routing::{get, post},
-    Router,
-};
 use axum_server::tls_rustls::RustlsConfig;
 use clap::Parser;
-use q_bot::{
-    handlers::{assistant_handler, events_handler, index_page},
-    AppState, Args,
-};
-use tower_http::services::ServeDir;
-use tracing::info;
+use q_bot::{app, preflight::preflight, AppState, Args};
+use tracing::{info, warn};
+
+// how often old and oversized assets are swept from disk
+const ASSET_SWEEP_INTERVAL: Duration = Duration::from_secs(600);
 
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
 
     let args = Args::parse();
-    let state = Arc::new(AppState::default());
-
-    let app = Router::new()
-        .route("/", get(index_page))
-        .route("/events", get(events_handler))
-        .route("/assistant", post(assistant_handler))
-        .nest_service("/public", ServeDir::new("./html-ui/public"))
-        .nest_service("/assets", ServeDir::new("/tmp/qbot"))
-        .with_state(state);
+    preflight(&args)?;
+    let state = Arc::new(AppState::new(&args)?);
+
+    // drop the event channels of devices that went away
+    let janitor = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            let evicted = janitor.events.evict_idle();
+            if evicted > 0 {
+                info!("evicted {} idle event channels", evicted);
+            }
+        }
+    });
+
+    // enforce the asset retention limits, the first sweep also takes stock of the disk
+    let janitor = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(ASSET_SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            match janitor.assets.sweep(&janitor.storage).await {
+                Result::Ok(removed) if removed.files > 0 => {
+                    info!("swept {} assets, {} bytes", removed.files, removed.bytes)
+                }
+                Result::Ok(_) => {}
+                Err(err) => warn!("asset sweep failed: {}", err),
+            }
+            // the speech cache limit may have been lowered since the last run
+            match janitor.speech_cache.trim(&janitor.storage).await {
+                Result::Ok(evicted) if evicted > 0 => info!("evicted {} cached speeches", evicted),
+                Result::Ok(_) => {}
+                Err(err) => warn!("speech cache trim failed: {}", err),
+            }
+        }
+    });
+
+    let app = app(state);
 
     let addr = format!("0.0.0.0:{}", args.port);
     info!("Listening on https://{}", addr);
@@ -0,0 +1,234 @@
+use std::collections::VecDeque;
+
+use clap::ValueEnum;
+use dashmap::DashMap;
+use llm_sdk::{AssistantMessage, ChatCompletionMessage, ToolCall};
+
+/// how to shrink a conversation once it no longer fits in the token budget
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum TruncationPolicy {
+    /// drop the oldest turns until the history fits again
+    #[default]
+    DropOldest,
+    /// forget everything but the latest turn
+    Reset,
+}
+
+/// per device conversation history, fed back to the model on every request
+#[derive(Debug)]
+pub struct ConversationStore {
+    conversations: DashMap<String, Conversation>,
+    max_tokens: usize,
+    policy: TruncationPolicy,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Conversation {
+    turns: VecDeque<Turn>,
+}
+
+/// one user utterance and everything Q did about it
+#[derive(Debug, Clone, Default)]
+pub struct Turn {
+    pub input: String,
+    pub tool_calls: Vec<ToolRecord>,
+    pub reply: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ToolRecord {
+    pub call: ToolCall,
+    pub output: String,
+}
+
+impl ConversationStore {
+    pub fn new(max_tokens: usize, policy: TruncationPolicy) -> Self {
+        Self {
+            conversations: DashMap::new(),
+            max_tokens,
+            policy,
+        }
+    }
+
+    /// history of the device rendered as chat messages, oldest first
+    pub fn messages(&self, device_id: &str, user_name: &str) -> Vec<ChatCompletionMessage> {
+        self.conversations
+            .get(device_id)
+            .map(|v| v.messages(user_name))
+            .unwrap_or_default()
+    }
+
+    /// append a finished turn and truncate the history to the token budget
+    pub fn push(&self, device_id: &str, turn: Turn) {
+        if self.max_tokens == 0 {
+            return;
+        }
+
+        let mut conversation = self.conversations.entry(device_id.to_string()).or_default();
+        conversation.turns.push_back(turn);
+        conversation.truncate(self.max_tokens, self.policy);
+    }
+
+    pub fn clear(&self, device_id: &str) {
+        self.conversations.remove(device_id);
+    }
+}
+
+impl Conversation {
+    pub fn messages(&self, user_name: &str) -> Vec<ChatCompletionMessage> {
+        self.turns
+            .iter()
+            .flat_map(|turn| turn.messages(user_name))
+            .collect()
+    }
+
+    pub fn tokens(&self) -> usize {
+        self.turns.iter().map(Turn::tokens).sum()
+    }
+
+    fn truncate(&mut self, max_tokens: usize, policy: TruncationPolicy) {
+        if self.tokens() <= max_tokens {
+            return;
+        }
+
+        match policy {
+            TruncationPolicy::DropOldest => {
+                while self.tokens() > max_tokens {
+                    self.turns.pop_front();
+                }
+            }
+            TruncationPolicy::Reset => {
+                let latest = self.turns.pop_back();
+                self.turns.clear();
+                self.turns
+                    .extend(latest.filter(|v| v.tokens() <= max_tokens));
+            }
+        }
+    }
+}
+
+impl Turn {
+    pub fn new(input: impl Into<String>) -> Self {
+        Self {
+            input: input.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_tool_call(mut self, call: ToolCall, output: impl Into<String>) -> Self {
+        self.tool_calls.push(ToolRecord {
+            call,
+            output: output.into(),
+        });
+        self
+    }
+
+    pub fn with_reply(mut self, reply: impl Into<String>) -> Self {
+        self.reply = Some(reply.into());
+        self
+    }
+
+    // llm-sdk can't build `tool` role messages, so tool outputs are replayed as assistant
+    // messages named after the tool that produced them
+    pub fn messages(&self, user_name: &str) -> Vec<ChatCompletionMessage> {
+        let mut messages = vec![ChatCompletionMessage::new_user(&self.input, user_name)];
+        messages.extend(
+            self.tool_calls
+                .iter()
+                .map(|v| assistant_message(v.output.clone(), Some(v.call.function.name.clone()))),
+        );
+        if let Some(reply) = &self.reply {
+            messages.push(assistant_message(reply.clone(), None));
+        }
+
+        messages
+    }
+
+    fn tokens(&self) -> usize {
+        let tools: usize = self
+            .tool_calls
+            .iter()
+            .map(|v| estimate_tokens(&v.call.function.name) + estimate_tokens(&v.output))
+            .sum();
+        let reply = self
+            .reply
+            .as_deref()
+            .map(estimate_tokens)
+            .unwrap_or_default();
+
+        estimate_tokens(&self.input) + tools + reply
+    }
+}
+
+fn assistant_message(content: String, name: Option<String>) -> ChatCompletionMessage {
+    ChatCompletionMessage::Assistant(AssistantMessage {
+        content: Some(content),
+        name,
+        tool_calls: vec![],
+    })
+}
+
+/// rough token count: ~4 ascii chars per token, one token per CJK char
+pub fn estimate_tokens(text: &str) -> usize {
+    let (ascii, other): (usize, usize) = text.chars().fold((0, 0), |(ascii, other), c| {
+        if c.is_ascii() {
+            (ascii + 1, other)
+        } else {
+            (ascii, other + 1)
+        }
+    });
+
+    ascii.div_ceil(4) + other
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every turn is 8 tokens: 16 ascii chars of input and of reply
+    fn turn(c: char) -> Turn {
+        Turn::new(c.to_string().repeat(16)).with_reply(c.to_string().repeat(16))
+    }
+
+    #[test]
+    fn test_estimate_tokens() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("hello"), 2);
+        assert_eq!(estimate_tokens("你好"), 2);
+    }
+
+    #[test]
+    fn test_drop_oldest_keeps_recent_turns() {
+        let store = ConversationStore::new(16, TruncationPolicy::DropOldest);
+        store.push("d", turn('a'));
+        store.push("d", turn('b'));
+        store.push("d", turn('c'));
+
+        let messages = store.messages("d", "zheng");
+        assert_eq!(messages.len(), 4);
+        let json = serde_json::to_string(&messages).unwrap();
+        assert!(!json.contains("aaaa"));
+        assert!(json.contains("bbbb"));
+        assert!(json.contains("cccc"));
+    }
+
+    #[test]
+    fn test_reset_keeps_latest_turn() {
+        let store = ConversationStore::new(16, TruncationPolicy::Reset);
+        store.push("d", turn('a'));
+        store.push("d", turn('b'));
+        store.push("d", turn('c'));
+
+        let messages = store.messages("d", "zheng");
+        assert_eq!(messages.len(), 2);
+        let json = serde_json::to_string(&messages).unwrap();
+        assert!(json.contains("cccc"));
+    }
+
+    #[test]
+    fn test_zero_budget_disables_memory() {
+        let store = ConversationStore::new(0, TruncationPolicy::DropOldest);
+        store.push("d", turn('a'));
+        assert!(store.messages("d", "zheng").is_empty());
+    }
+}
@@ -0,0 +1,125 @@
+use std::{fs, path::Path};
+
+use anyhow::{bail, Result};
+
+use crate::{config::Config, llm::SDK_TIMEOUT_SECS, Args};
+
+/// the api key the OpenAI backend authenticates with
+pub const API_KEY_VAR: &str = "OPENAI_API_KEY";
+
+/// everything the server needs before it starts, all problems are reported at once
+pub fn preflight(args: &Args) -> Result<()> {
+    let problems = check(args, std::env::var(API_KEY_VAR).ok());
+    if !problems.is_empty() {
+        bail!(
+            "the server can't start:\n{}",
+            problems
+                .iter()
+                .map(|v| format!("  - {}", v))
+                .collect::<Vec<_>>()
+                .join("\n")
+        );
+    }
+
+    Ok(())
+}
+
+fn check(args: &Args, api_key: Option<String>) -> Vec<String> {
+    let mut problems = vec![];
+
+    if api_key.is_none_or(|v| v.trim().is_empty()) {
+        problems.push(format!("{} is not set", API_KEY_VAR));
+    }
+
+    if let Err(err) = Config::load(args.config.as_ref()) {
+        problems.push(format!("{:#}", err));
+    }
+
+    for file in ["cert.pem", "key.pem"] {
+        let path = Path::new(&args.cert_path).join(file);
+        if !path.is_file() {
+            problems.push(format!("tls {} not found at {}", file, path.display()));
+        }
+    }
+
+    for (flag, v) in [
+        ("device-daily-cost", args.device_daily_cost),
+        ("global-daily-cost", args.global_daily_cost),
+    ] {
+        if v.is_nan() || v < 0.0 {
+            problems.push(format!("--{} must be 0 or more, got {}", flag, v));
+        }
+    }
+
+    // llm-sdk times out on its own after this, a longer wait would never happen
+    let sdk = Some(SDK_TIMEOUT_SECS);
+    for (flag, v, max) in [
+        ("transcription-timeout", args.transcription_timeout, sdk),
+        ("thinking-timeout", args.thinking_timeout, sdk),
+        ("completion-timeout", args.completion_timeout, None),
+        ("speech-timeout", args.speech_timeout, sdk),
+        ("image-timeout", args.image_timeout, sdk),
+    ] {
+        if v.is_nan() || v <= 0.0 || max.is_some_and(|max| v > max) {
+            let at_most = max.map(|v| format!(" and at most {}", v));
+            problems.push(format!(
+                "--{} must be more than 0{}, got {}",
+                flag,
+                at_most.unwrap_or_default(),
+                v
+            ));
+        }
+    }
+
+    let mut dirs = vec![Path::new(&args.asset_dir)];
+    if args.database != ":memory:" {
+        dirs.extend(Path::new(&args.database).parent());
+    }
+    for dir in dirs {
+        if let Err(err) = fs::create_dir_all(dir) {
+            problems.push(format!("can't create {}: {}", dir.display(), err));
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn test_check_reports_every_problem() {
+        let args = Args::parse_from([
+            "qbot",
+            "--cert-path",
+            "/nonexistent/certs",
+            "--config",
+            "/nonexistent/qbot.toml",
+            "--database",
+            ":memory:",
+            "--asset-dir",
+            std::env::temp_dir().to_str().unwrap(),
+            "--image-timeout",
+            "120",
+            "--completion-timeout",
+            "0",
+        ]);
+
+        let problems = check(&args, Some(" ".into()));
+        assert_eq!(problems.len(), 6);
+        assert_eq!(problems[0], "OPENAI_API_KEY is not set");
+        assert!(problems[1].contains("failed to read config /nonexistent/qbot.toml"));
+        assert!(problems[2].contains("cert.pem"));
+        assert!(problems[3].contains("key.pem"));
+        assert_eq!(
+            problems[4],
+            "--completion-timeout must be more than 0, got 0"
+        );
+        assert_eq!(
+            problems[5],
+            "--image-timeout must be more than 0 and at most 30, got 120"
+        );
+    }
+}
@@ -0,0 +1,308 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+};
+
+use anyhow::Result;
+use llm_sdk::SpeechRequest;
+use lru::LruCache;
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use tracing::warn;
+
+use crate::storage::Storage;
+
+// fragments shorter than this are spoken together with the next sentence
+const MIN_SEGMENT_CHARS: usize = 16;
+
+/// content addressed cache of synthesized speech, the least recently used files go first
+#[derive(Debug)]
+pub struct SpeechCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Debug)]
+struct Entries {
+    // key -> file size
+    lru: LruCache<String, u64>,
+    bytes: u64,
+}
+
+impl SpeechCache {
+    /// index the files already in `dir`, oldest first, `trim` evicts those over the limit;
+    /// a `max_bytes` of 0 disables the cache
+    pub fn open(dir: impl Into<PathBuf>, max_bytes: u64) -> Result<Self> {
+        let dir = dir.into();
+        let mut files = vec![];
+        if max_bytes > 0 && dir.exists() {
+            for entry in std::fs::read_dir(&dir)? {
+                let entry = entry?;
+                let meta = entry.metadata()?;
+                let path = entry.path();
+                if !meta.is_file() || path.extension().is_some_and(|v| v != "mp3") {
+                    continue;
+                }
+                if let Some(key) = path.file_stem().and_then(|v| v.to_str()) {
+                    files.push((meta.modified()?, key.to_string(), meta.len()));
+                }
+            }
+        }
+        files.sort();
+
+        let mut entries = Entries {
+            lru: LruCache::unbounded(),
+            bytes: 0,
+        };
+        for (_, key, size) in files {
+            entries.bytes += size;
+            entries.lru.put(key, size);
+        }
+
+        Ok(Self {
+            dir,
+            max_bytes,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_bytes > 0
+    }
+
+    /// everything that changes the audio is part of the request, so it is hashed as a whole
+    pub fn key(req: &SpeechRequest) -> Result<String> {
+        let hash = Sha256::digest(serde_json::to_vec(req)?);
+        Ok(format!("{:x}", hash))
+    }
+
+    /// the cached audio for `key`, if any
+    pub fn get(&self, key: &str) -> Option<PathBuf> {
+        let path = path(&self.dir, key);
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        entries.lru.get(key)?;
+        if !path.exists() {
+            // removed behind our back
+            let size = entries.lru.pop(key).unwrap_or_default();
+            entries.bytes = entries.bytes.saturating_sub(size);
+            return None;
+        }
+
+        Some(path)
+    }
+
+    /// store `audio` under `key`, then evict the least recently used files over the size limit
+    pub async fn put(&self, storage: &Storage, key: &str, audio: &[u8]) -> Result<PathBuf> {
+        let path = path(&self.dir, key);
+        fs::create_dir_all(&self.dir).await?;
+        fs::write(&path, audio).await?;
+
+        {
+            let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+            let size = audio.len() as u64;
+            if let Some(old) = entries.lru.put(key.to_string(), size) {
+                entries.bytes = entries.bytes.saturating_sub(old);
+            }
+            entries.bytes += size;
+        }
+        self.trim(storage).await?;
+
+        Ok(path)
+    }
+
+    /// evict the least recently used files over the size limit, returns how many went
+    ///
+    /// the devices they were spoken to lose them as assets too
+    pub async fn trim(&self, storage: &Storage) -> Result<usize> {
+        let evicted = self
+            .entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .evict(&self.dir, self.max_bytes);
+        for path in &evicted {
+            if let Err(err) = fs::remove_file(path).await {
+                warn!("failed to evict {}: {}", path.display(), err);
+            }
+        }
+
+        let count = evicted.len();
+        storage.forget_assets(evicted).await?;
+        Ok(count)
+    }
+
+    /// bytes of audio in the cache
+    pub fn bytes(&self) -> u64 {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .bytes
+    }
+}
+
+impl Entries {
+    // the most recent entry always stays, even if it alone is over the limit
+    fn evict(&mut self, dir: &Path, max_bytes: u64) -> Vec<PathBuf> {
+        let mut evicted = vec![];
+        while self.bytes > max_bytes && self.lru.len() > 1 {
+            let Some((key, size)) = self.lru.pop_lru() else {
+                break;
+            };
+            self.bytes = self.bytes.saturating_sub(size);
+            evicted.push(path(dir, &key));
+        }
+
+        evicted
+    }
+}
+
+fn path(dir: &Path, key: &str) -> PathBuf {
+    dir.join(format!("{}.mp3", key))
+}
+
+/// split a reply into the segments it is spoken in, each keeps its punctuation
+pub fn split_sentences(text: &str) -> Vec<String> {
+    let mut segments = vec![];
+    let mut current = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        current.push(c);
+        if !is_terminator(c) {
+            continue;
+        }
+
+        // closing quotes and brackets belong to the sentence they close
+        while let Some(&c) = chars.peek() {
+            if !is_closing(c) {
+                break;
+            }
+            current.push(c);
+            chars.next();
+        }
+        // `3.14` or `e.g.x` aren't sentence ends, ascii punctuation needs a space after it
+        if c.is_ascii() && chars.peek().is_some_and(|v| !v.is_whitespace()) {
+            continue;
+        }
+        if current.trim().chars().count() >= MIN_SEGMENT_CHARS {
+            segments.push(current.trim().to_string());
+            current.clear();
+        }
+    }
+    if !current.trim().is_empty() {
+        segments.push(current.trim().to_string());
+    }
+
+    segments
+}
+
+fn is_terminator(c: char) -> bool {
+    matches!(
+        c,
+        '.' | '!' | '?' | ';' | '\n' | '。' | '！' | '？' | '；' | '…'
+    )
+}
+
+fn is_closing(c: char) -> bool {
+    matches!(c, '"' | '\'' | ')' | '”' | '’' | '」' | '』' | '）' | '》')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::AssetKind;
+    use uuid::Uuid;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("qbot-speech-{}", Uuid::new_v4()))
+    }
+
+    #[test]
+    fn test_split_english() {
+        let text = "Rust is a systems language. It is fast! Is it safe? Yes, memory safe.";
+        assert_eq!(
+            split_sentences(text),
+            vec![
+                "Rust is a systems language.",
+                "It is fast! Is it safe?",
+                "Yes, memory safe."
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_chinese() {
+        let text =
+            "Rust 是一门系统编程语言，性能很好。它保证内存安全，而且没有垃圾回收！你想学吗？";
+        assert_eq!(
+            split_sentences(text),
+            vec![
+                "Rust 是一门系统编程语言，性能很好。",
+                "它保证内存安全，而且没有垃圾回收！",
+                "你想学吗？"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_keeps_numbers_and_quotes() {
+        let text =
+            "Pi is about 3.14159 in most cases. He said \"that is enough precision.\" Then left.";
+        assert_eq!(
+            split_sentences(text),
+            vec![
+                "Pi is about 3.14159 in most cases.",
+                "He said \"that is enough precision.\"",
+                "Then left."
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_short_and_empty() {
+        assert_eq!(split_sentences("Hi."), vec!["Hi."]);
+        assert!(split_sentences("  \n ").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cache_evicts_least_recently_used() -> Result<()> {
+        let dir = temp_dir();
+        let storage = Storage::open_in_memory()?;
+        let cache = SpeechCache::open(&dir, 10)?;
+        let a = cache.put(&storage, "a", b"aaaa").await?;
+        let b = cache.put(&storage, "b", b"bbbb").await?;
+        for path in [&a, &b] {
+            storage
+                .save_asset("d", "r1", AssetKind::Audio, path)
+                .await?;
+        }
+        // `a` is used again, so `b` is the one to go
+        assert!(cache.get("a").is_some());
+        cache.put(&storage, "c", b"cccc").await?;
+
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert!(!dir.join("b.mp3").exists());
+        assert_eq!(cache.bytes(), 8);
+        // nor is it an asset of the device it was spoken to anymore
+        assert!(storage.owns_asset("d", &a).await?);
+        assert!(!storage.owns_asset("d", &b).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cache_reopens_from_disk() -> Result<()> {
+        let dir = temp_dir();
+        let storage = Storage::open_in_memory()?;
+        let cache = SpeechCache::open(&dir, 100)?;
+        cache.put(&storage, "a", b"aaaa").await?;
+        cache.put(&storage, "b", b"bbbb").await?;
+
+        // a lower limit keeps only the newest file
+        let cache = SpeechCache::open(&dir, 6)?;
+        assert_eq!(cache.trim(&storage).await?, 1);
+        assert!(cache.get("a").is_none());
+        assert!(cache.get("b").is_some());
+        assert_eq!(cache.bytes(), 4);
+        Ok(())
+    }
+}
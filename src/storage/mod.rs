@@ -0,0 +1,734 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, TimeZone, Utc};
+use rusqlite::{params, Connection};
+use serde::{de::DeserializeOwned, Serialize};
+use strum::{Display, EnumString};
+
+use crate::{
+    error::AppError,
+    handlers::{ChatInputEvent, ChatReplyEvent},
+    usage::{Usage, UsageRecord},
+};
+
+// every entry is applied once, in order, and tracked by `PRAGMA user_version`
+const MIGRATIONS: &[&str] = &[
+    r#"
+    CREATE TABLE chat_events (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        device_id TEXT NOT NULL,
+        request_id TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        payload TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    CREATE INDEX chat_events_device_id ON chat_events (device_id, id);
+
+    CREATE TABLE assets (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        device_id TEXT NOT NULL,
+        request_id TEXT NOT NULL,
+        kind TEXT NOT NULL,
+        path TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    CREATE INDEX assets_device_id ON assets (device_id, request_id);
+    "#,
+    // removed files keep their row, the daily image quota still counts them
+    r#"
+    ALTER TABLE assets ADD COLUMN deleted_at INTEGER;
+    CREATE INDEX assets_path ON assets (path);
+    "#,
+    // what each device consumed, summed up per day (UTC) and model
+    r#"
+    CREATE TABLE usage (
+        device_id TEXT NOT NULL,
+        day TEXT NOT NULL,
+        model TEXT NOT NULL,
+        prompt_tokens INTEGER NOT NULL DEFAULT 0,
+        completion_tokens INTEGER NOT NULL DEFAULT 0,
+        audio_seconds REAL NOT NULL DEFAULT 0,
+        speech_chars INTEGER NOT NULL DEFAULT 0,
+        images INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (device_id, day, model)
+    );
+    CREATE INDEX usage_day ON usage (day);
+    "#,
+    // recent requests, for the rate limits to outlive restarts
+    r#"
+    CREATE TABLE requests (
+        device_id TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    CREATE INDEX requests_device_id ON requests (device_id, created_at);
+    CREATE INDEX requests_created_at ON requests (created_at);
+    "#,
+    // the estimated cost is kept with the usage, for the daily caps to add it up in place;
+    // usage from before counts as free
+    r#"
+    ALTER TABLE usage ADD COLUMN cost REAL NOT NULL DEFAULT 0;
+    DROP INDEX usage_day;
+    CREATE INDEX usage_day_device_id ON usage (day, device_id);
+    "#,
+];
+
+/// chat history repository backed by an embedded sqlite database
+#[derive(Debug, Clone)]
+pub struct Storage {
+    conn: Arc<Mutex<Connection>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum EventKind {
+    Input,
+    Reply,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum AssetKind {
+    Audio,
+    Image,
+}
+
+/// a stored chat event, `event` is either a `ChatInputEvent` or a `ChatReplyEvent`
+#[derive(Debug, Clone)]
+pub struct EventRecord<T> {
+    pub id: i64,
+    pub request_id: String,
+    pub created_at: DateTime<Utc>,
+    pub event: T,
+}
+
+#[derive(Debug, Clone)]
+pub struct AssetRecord {
+    pub id: i64,
+    pub request_id: String,
+    pub kind: AssetKind,
+    pub path: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// requests logged within a window, of one device and of every device
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RequestCount {
+    pub device: u64,
+    pub total: u64,
+}
+
+/// tokens and dollars spent on a day, by one device and by every device
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Spent {
+    pub device: (u64, f64),
+    pub total: (u64, f64),
+}
+
+#[derive(Debug, Clone)]
+pub enum StoredEvent {
+    Input(EventRecord<ChatInputEvent>),
+    Reply(EventRecord<ChatReplyEvent>),
+}
+
+impl Storage {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        Self::init(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(mut conn: Connection) -> Result<Self> {
+        migrate(&mut conn)?;
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    pub async fn save_input(&self, device_id: &str, event: &ChatInputEvent) -> Result<i64> {
+        self.save_event(device_id, &event.id, EventKind::Input, event)
+            .await
+    }
+
+    pub async fn save_reply(&self, device_id: &str, event: &ChatReplyEvent) -> Result<i64> {
+        self.save_event(device_id, &event.id, EventKind::Reply, event)
+            .await
+    }
+
+    pub async fn save_asset(
+        &self,
+        device_id: &str,
+        request_id: &str,
+        kind: AssetKind,
+        path: impl AsRef<Path>,
+    ) -> Result<i64> {
+        let device_id = device_id.to_string();
+        let request_id = request_id.to_string();
+        let path = path.as_ref().to_string_lossy().to_string();
+
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT INTO assets (device_id, request_id, kind, path, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![device_id, request_id, kind.to_string(), path, now()],
+            )?;
+            Ok(conn.last_insert_rowid())
+        })
+        .await
+    }
+
+    /// events of a device, newest first, optionally only those older than `before`
+    pub async fn events(
+        &self,
+        device_id: &str,
+        before: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<StoredEvent>> {
+        let device_id = device_id.to_string();
+        let before = before.unwrap_or(i64::MAX);
+
+        self.call(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, request_id, kind, payload, created_at FROM chat_events
+                 WHERE device_id = ?1 AND id < ?2 ORDER BY id DESC LIMIT ?3",
+            )?;
+            let rows = stmt.query_map(params![device_id, before, limit as i64], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, i64>(4)?,
+                ))
+            })?;
+
+            let mut events = vec![];
+            for row in rows {
+                let (id, request_id, kind, payload, created_at) = row?;
+                let event = match kind.parse()? {
+                    EventKind::Input => {
+                        StoredEvent::Input(record(id, request_id, &payload, created_at)?)
+                    }
+                    EventKind::Reply => {
+                        StoredEvent::Reply(record(id, request_id, &payload, created_at)?)
+                    }
+                };
+                events.push(event);
+            }
+
+            Ok(events)
+        })
+        .await
+    }
+
+    pub async fn assets(&self, device_id: &str) -> Result<Vec<AssetRecord>> {
+        let device_id = device_id.to_string();
+
+        self.call(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, request_id, kind, path, created_at FROM assets
+                 WHERE device_id = ?1 AND deleted_at IS NULL ORDER BY id",
+            )?;
+            let rows = stmt.query_map(params![device_id], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, i64>(4)?,
+                ))
+            })?;
+
+            let mut assets = vec![];
+            for row in rows {
+                let (id, request_id, kind, path, created_at) = row?;
+                assets.push(AssetRecord {
+                    id,
+                    request_id,
+                    kind: kind.parse()?,
+                    path,
+                    created_at: timestamp(created_at)?,
+                });
+            }
+
+            Ok(assets)
+        })
+        .await
+    }
+
+    /// whether the device has a live asset at `path`
+    pub async fn owns_asset(&self, device_id: &str, path: impl AsRef<Path>) -> Result<bool> {
+        let device_id = device_id.to_string();
+        let path = path.as_ref().to_string_lossy().to_string();
+
+        self.call(move |conn| {
+            let owned = conn.query_row(
+                "SELECT EXISTS (SELECT 1 FROM assets
+                 WHERE device_id = ?1 AND path = ?2 AND deleted_at IS NULL)",
+                params![device_id, path],
+                |row| row.get(0),
+            )?;
+            Ok(owned)
+        })
+        .await
+    }
+
+    /// number of `kind` assets the device created since `since`
+    pub async fn count_assets(
+        &self,
+        device_id: &str,
+        kind: AssetKind,
+        since: DateTime<Utc>,
+    ) -> Result<usize> {
+        let device_id = device_id.to_string();
+
+        self.call(move |conn| {
+            let count: i64 = conn.query_row(
+                "SELECT COUNT(*) FROM assets WHERE device_id = ?1 AND kind = ?2 AND created_at >= ?3",
+                params![device_id, kind.to_string(), since.timestamp_millis()],
+                |row| row.get(0),
+            )?;
+            Ok(count as usize)
+        })
+        .await
+    }
+
+    /// mark the assets at `paths` as removed from disk
+    pub async fn forget_assets(&self, paths: Vec<PathBuf>) -> Result<usize> {
+        if paths.is_empty() {
+            return Ok(0);
+        }
+
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            let mut forgotten = 0;
+            {
+                let mut stmt = tx.prepare(
+                    "UPDATE assets SET deleted_at = ?1 WHERE path = ?2 AND deleted_at IS NULL",
+                )?;
+                let now = now();
+                for path in paths {
+                    forgotten += stmt.execute(params![now, path.to_string_lossy()])?;
+                }
+            }
+            tx.commit()?;
+            Ok(forgotten)
+        })
+        .await
+    }
+
+    /// drop the chat history of a device, its assets are only marked as removed
+    pub async fn delete_device(&self, device_id: &str) -> Result<()> {
+        let device_id = device_id.to_string();
+
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "DELETE FROM chat_events WHERE device_id = ?1",
+                params![device_id],
+            )?;
+            tx.execute(
+                "UPDATE assets SET deleted_at = ?1 WHERE device_id = ?2 AND deleted_at IS NULL",
+                params![now(), device_id],
+            )?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// add `usage` of `model`, and the `cost` estimated for it, to what the device consumed today
+    pub async fn record_usage(
+        &self,
+        device_id: &str,
+        model: &str,
+        usage: Usage,
+        cost: f64,
+    ) -> Result<()> {
+        let device_id = device_id.to_string();
+        let model = model.to_string();
+
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT INTO usage (device_id, day, model, prompt_tokens, completion_tokens,
+                    audio_seconds, speech_chars, images, cost)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+                 ON CONFLICT (device_id, day, model) DO UPDATE SET
+                    prompt_tokens = prompt_tokens + excluded.prompt_tokens,
+                    completion_tokens = completion_tokens + excluded.completion_tokens,
+                    audio_seconds = audio_seconds + excluded.audio_seconds,
+                    speech_chars = speech_chars + excluded.speech_chars,
+                    images = images + excluded.images,
+                    cost = cost + excluded.cost",
+                params![
+                    device_id,
+                    today(),
+                    model,
+                    usage.prompt_tokens,
+                    usage.completion_tokens,
+                    usage.audio_seconds,
+                    usage.speech_chars,
+                    usage.images,
+                    cost
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// usage of every device on `since` (`YYYY-MM-DD`) and after
+    pub async fn usage(&self, since: &str) -> Result<Vec<UsageRecord>> {
+        let since = since.to_string();
+
+        self.call(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT device_id, day, model, prompt_tokens, completion_tokens, audio_seconds,
+                    speech_chars, images, cost
+                 FROM usage WHERE day >= ?1",
+            )?;
+            let rows = stmt.query_map(params![since], |row| {
+                Ok(UsageRecord {
+                    device_id: row.get(0)?,
+                    day: row.get(1)?,
+                    model: row.get(2)?,
+                    usage: Usage {
+                        prompt_tokens: row.get(3)?,
+                        completion_tokens: row.get(4)?,
+                        audio_seconds: row.get(5)?,
+                        speech_chars: row.get(6)?,
+                        images: row.get(7)?,
+                    },
+                    cost: row.get(8)?,
+                })
+            })?;
+            Ok(rows.collect::<Result<_, _>>()?)
+        })
+        .await
+    }
+
+    /// tokens and cost of `day` (`YYYY-MM-DD`), of `device_id` and of every device
+    pub async fn spent(&self, device_id: &str, day: &str) -> Result<Spent> {
+        let device_id = device_id.to_string();
+        let day = day.to_string();
+
+        self.call(move |conn| {
+            Ok(conn.query_row(
+                "SELECT
+                    COALESCE(SUM(CASE WHEN device_id = ?1
+                        THEN prompt_tokens + completion_tokens END), 0),
+                    COALESCE(SUM(CASE WHEN device_id = ?1 THEN cost END), 0.0),
+                    COALESCE(SUM(prompt_tokens + completion_tokens), 0),
+                    COALESCE(SUM(cost), 0.0)
+                 FROM usage WHERE day = ?2",
+                params![device_id, day],
+                |row| {
+                    Ok(Spent {
+                        device: (row.get(0)?, row.get(1)?),
+                        total: (row.get(2)?, row.get(3)?),
+                    })
+                },
+            )?)
+        })
+        .await
+    }
+
+    /// log a request of `device_id` if `admit` allows it, given the requests of the last `window`
+    ///
+    /// returns the requests counted before this one, older entries are pruned along the way
+    pub async fn log_request<F>(
+        &self,
+        device_id: &str,
+        window: Duration,
+        admit: F,
+    ) -> Result<RequestCount>
+    where
+        F: FnOnce(RequestCount) -> bool + Send + 'static,
+    {
+        let device_id = device_id.to_string();
+        let now = Utc::now().timestamp_millis();
+        let since = now - window.as_millis() as i64;
+
+        self.call(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute(
+                "DELETE FROM requests WHERE created_at <= ?1",
+                params![since],
+            )?;
+            let count = tx.query_row(
+                "SELECT COUNT(*), COALESCE(SUM(device_id = ?1), 0) FROM requests",
+                params![device_id],
+                |row| {
+                    Ok(RequestCount {
+                        total: row.get(0)?,
+                        device: row.get(1)?,
+                    })
+                },
+            )?;
+            if admit(count) {
+                tx.execute(
+                    "INSERT INTO requests (device_id, created_at) VALUES (?1, ?2)",
+                    params![device_id, now],
+                )?;
+            }
+            tx.commit()?;
+            Ok(count)
+        })
+        .await
+    }
+
+    async fn save_event(
+        &self,
+        device_id: &str,
+        request_id: &str,
+        kind: EventKind,
+        event: &impl Serialize,
+    ) -> Result<i64> {
+        let device_id = device_id.to_string();
+        let request_id = request_id.to_string();
+        let payload = serde_json::to_string(event)?;
+
+        self.call(move |conn| {
+            conn.execute(
+                "INSERT INTO chat_events (device_id, request_id, kind, payload, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![device_id, request_id, kind.to_string(), payload, now()],
+            )?;
+            Ok(conn.last_insert_rowid())
+        })
+        .await
+    }
+
+    // sqlite is blocking, keep it off the async workers
+    async fn call<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().map_err(|_| anyhow!("storage lock poisoned"))?;
+            f(&mut conn)
+        })
+        .await?
+        .map_err(|err| AppError::Storage(err).into())
+    }
+}
+
+impl StoredEvent {
+    pub fn id(&self) -> i64 {
+        match self {
+            StoredEvent::Input(v) => v.id,
+            StoredEvent::Reply(v) => v.id,
+        }
+    }
+}
+
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+    }
+
+    Ok(())
+}
+
+fn record<T: DeserializeOwned>(
+    id: i64,
+    request_id: String,
+    payload: &str,
+    created_at: i64,
+) -> Result<EventRecord<T>> {
+    Ok(EventRecord {
+        id,
+        request_id,
+        created_at: timestamp(created_at)?,
+        event: serde_json::from_str(payload)?,
+    })
+}
+
+fn now() -> i64 {
+    Utc::now().timestamp_millis()
+}
+
+/// the current day (UTC), as usage is keyed by
+pub fn today() -> String {
+    Utc::now().format("%Y-%m-%d").to_string()
+}
+
+fn timestamp(millis: i64) -> Result<DateTime<Utc>> {
+    Utc.timestamp_millis_opt(millis)
+        .single()
+        .ok_or_else(|| anyhow!("invalid timestamp {}", millis))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::SpeechResult;
+
+    #[tokio::test]
+    async fn test_events_roundtrip() -> Result<()> {
+        let storage = Storage::open_in_memory()?;
+        storage
+            .save_input("d", &ChatInputEvent::new("r1", "hello"))
+            .await?;
+        storage
+            .save_reply(
+                "d",
+                &ChatReplyEvent::new(
+                    "r1",
+                    SpeechResult::with_playlist("hi", vec!["/a.mp3".into()]),
+                ),
+            )
+            .await?;
+        storage
+            .save_input("other", &ChatInputEvent::new("r2", "ignored"))
+            .await?;
+
+        let events = storage.events("d", None, 10).await?;
+        assert_eq!(events.len(), 2);
+        assert!(matches!(&events[0], StoredEvent::Reply(v) if v.request_id == "r1"));
+        let StoredEvent::Input(input) = &events[1] else {
+            panic!("expect an input event");
+        };
+        assert_eq!(input.event.content, "hello");
+
+        let older = storage.events("d", Some(input.id), 10).await?;
+        assert!(older.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_assets() -> Result<()> {
+        let storage = Storage::open_in_memory()?;
+        storage
+            .save_asset("d", "r1", AssetKind::Audio, "/tmp/qbot/audio/d/a.mp3")
+            .await?;
+
+        let assets = storage.assets("d").await?;
+        assert_eq!(assets.len(), 1);
+        assert_eq!(assets[0].kind, AssetKind::Audio);
+        assert_eq!(assets[0].path, "/tmp/qbot/audio/d/a.mp3");
+
+        let since = assets[0].created_at;
+        assert_eq!(storage.count_assets("d", AssetKind::Audio, since).await?, 1);
+        assert_eq!(storage.count_assets("d", AssetKind::Image, since).await?, 0);
+        let later = since + chrono::Duration::seconds(1);
+        assert_eq!(storage.count_assets("d", AssetKind::Audio, later).await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete_device() -> Result<()> {
+        let storage = Storage::open_in_memory()?;
+        storage
+            .save_input("d", &ChatInputEvent::new("r1", "hello"))
+            .await?;
+        storage
+            .save_asset("d", "r1", AssetKind::Image, "/a.png")
+            .await?;
+        storage
+            .save_asset("d", "r1", AssetKind::Image, "/b.png")
+            .await?;
+        let since = storage.assets("d").await?[0].created_at;
+
+        assert!(storage.owns_asset("d", "/a.png").await?);
+        assert!(!storage.owns_asset("other", "/a.png").await?);
+        assert_eq!(storage.forget_assets(vec!["/a.png".into()]).await?, 1);
+        assert_eq!(storage.assets("d").await?.len(), 1);
+        assert!(!storage.owns_asset("d", "/a.png").await?);
+
+        storage.delete_device("d").await?;
+        assert!(storage.events("d", None, 10).await?.is_empty());
+        assert!(storage.assets("d").await?.is_empty());
+        // removed images still count towards the daily quota
+        assert_eq!(storage.count_assets("d", AssetKind::Image, since).await?, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_usage_adds_up_per_day_and_model() -> Result<()> {
+        let storage = Storage::open_in_memory()?;
+        storage
+            .record_usage("d", "gpt-3.5-turbo-1106", Usage::tokens(10, 5), 0.25)
+            .await?;
+        storage
+            .record_usage("d", "gpt-3.5-turbo-1106", Usage::tokens(1, 2), 0.25)
+            .await?;
+        storage
+            .record_usage("d", "whisper-1", Usage::audio(1.5), 0.5)
+            .await?;
+        storage
+            .record_usage("e", "gpt-3.5-turbo-1106", Usage::tokens(100, 0), 2.0)
+            .await?;
+
+        let mut records = storage.usage(&today()).await?;
+        records.sort_by(|a, b| (&a.model, &a.device_id).cmp(&(&b.model, &b.device_id)));
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].usage, Usage::tokens(11, 7));
+        assert_eq!(records[0].cost, 0.5);
+        assert_eq!(records[2].usage, Usage::audio(1.5));
+        assert!(storage.usage("9999-01-01").await?.is_empty());
+
+        let spent = storage.spent("d", &today()).await?;
+        assert_eq!(spent.device, (18, 1.0));
+        assert_eq!(spent.total, (118, 3.0));
+        assert_eq!(storage.spent("d", "9999-01-01").await?, Spent::default());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_request_log_survives_reopening() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("qbot-{}.db", uuid::Uuid::new_v4()));
+        let minute = Duration::from_secs(60);
+        let storage = Storage::open(&path)?;
+        storage.log_request("a", minute, |_| true).await?;
+        storage.log_request("b", minute, |_| true).await?;
+        // turned away, so not counted
+        storage.log_request("a", minute, |_| false).await?;
+        drop(storage);
+
+        let storage = Storage::open(&path)?;
+        let count = storage.log_request("a", minute, |_| false).await?;
+        assert_eq!(
+            count,
+            RequestCount {
+                device: 1,
+                total: 2
+            }
+        );
+        // entries older than the window no longer count
+        let count = storage.log_request("a", Duration::ZERO, |_| false).await?;
+        assert_eq!(count, RequestCount::default());
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_is_idempotent() -> Result<()> {
+        let mut conn = Connection::open_in_memory()?;
+        migrate(&mut conn)?;
+        migrate(&mut conn)?;
+        let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        assert_eq!(version, MIGRATIONS.len());
+        Ok(())
+    }
+}
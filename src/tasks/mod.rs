@@ -0,0 +1,283 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use clap::ValueEnum;
+use dashmap::DashMap;
+use futures::future::{AbortHandle, Abortable};
+use tokio::sync::Mutex;
+
+/// what happens when a device asks something while Q is still busy answering it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum BargeIn {
+    /// wait for the running requests to finish first
+    Queue,
+    /// turn the new request down
+    Reject,
+    /// the latest request wins, the running ones are interrupted
+    #[default]
+    Interrupt,
+}
+
+/// why a request didn't run to the end
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stopped {
+    /// the user cancelled it
+    Cancelled,
+    /// a newer request took over
+    Interrupted,
+    /// turned down, another request was running
+    Busy,
+}
+
+/// requests in flight per device, so they can be cancelled before they finish
+#[derive(Debug, Default)]
+pub struct TaskRegistry {
+    // device id -> request id -> task
+    tasks: DashMap<String, HashMap<String, Task>>,
+    // requests of a device run one at a time when queued
+    queues: DashMap<String, Arc<Mutex<()>>>,
+    policy: BargeIn,
+}
+
+#[derive(Debug)]
+struct Task {
+    handle: AbortHandle,
+    interrupted: Arc<AtomicBool>,
+}
+
+// forgets a request once it finished, was stopped or its client went away
+struct Running<'a> {
+    registry: &'a TaskRegistry,
+    device_id: &'a str,
+    id: &'a str,
+}
+
+impl TaskRegistry {
+    pub fn new(policy: BargeIn) -> Self {
+        Self {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    /// run `fut` as request `id` of the device, following the barge-in policy
+    pub async fn run<F: Future>(
+        &self,
+        device_id: &str,
+        id: &str,
+        fut: F,
+    ) -> Result<F::Output, Stopped> {
+        let (handle, registration) = AbortHandle::new_pair();
+        let interrupted = Arc::new(AtomicBool::new(false));
+        {
+            let mut tasks = self.tasks.entry(device_id.to_string()).or_default();
+            match self.policy {
+                BargeIn::Reject if !tasks.is_empty() => return Err(Stopped::Busy),
+                BargeIn::Interrupt => tasks.drain().for_each(|(_, v)| v.interrupt()),
+                _ => {}
+            }
+            let task = Task {
+                handle,
+                interrupted: interrupted.clone(),
+            };
+            tasks.insert(id.to_string(), task);
+        }
+
+        let _running = Running {
+            registry: self,
+            device_id,
+            id,
+        };
+        let queue = (self.policy == BargeIn::Queue).then(|| {
+            let queue = self.queues.entry(device_id.to_string()).or_default();
+            queue.clone()
+        });
+        let fut = async {
+            // queued requests can be cancelled while they wait
+            let _turn = match &queue {
+                Some(queue) => Some(queue.lock().await),
+                None => None,
+            };
+            fut.await
+        };
+
+        Abortable::new(fut, registration).await.map_err(|_| {
+            if interrupted.load(Ordering::SeqCst) {
+                Stopped::Interrupted
+            } else {
+                Stopped::Cancelled
+            }
+        })
+    }
+
+    /// cancel a single request, false if the device has no such request running
+    pub fn cancel(&self, device_id: &str, id: &str) -> bool {
+        let task = self.tasks.get_mut(device_id).and_then(|mut v| v.remove(id));
+        match task {
+            Some(task) => {
+                task.handle.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// cancel every request of the device, returns how many there were
+    pub fn cancel_all(&self, device_id: &str) -> usize {
+        let Some((_, tasks)) = self.tasks.remove(device_id) else {
+            return 0;
+        };
+        for task in tasks.values() {
+            task.handle.abort();
+        }
+
+        tasks.len()
+    }
+
+    /// interrupt every request of the device, the user started talking over them
+    pub fn interrupt(&self, device_id: &str) -> usize {
+        let Some((_, tasks)) = self.tasks.remove(device_id) else {
+            return 0;
+        };
+        let n = tasks.len();
+        tasks.into_values().for_each(Task::interrupt);
+
+        n
+    }
+
+    /// ids of the requests the device has running
+    pub fn running(&self, device_id: &str) -> Vec<String> {
+        self.tasks
+            .get(device_id)
+            .map(|v| v.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+impl Task {
+    fn interrupt(self) {
+        self.interrupted.store(true, Ordering::SeqCst);
+        self.handle.abort();
+    }
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        let registry = self.registry;
+        if let Some(mut v) = registry.tasks.get_mut(self.device_id) {
+            v.remove(self.id);
+        }
+        registry
+            .tasks
+            .remove_if(self.device_id, |_, v| v.is_empty());
+        registry
+            .queues
+            .remove_if(self.device_id, |_, v| Arc::strong_count(v) == 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn spawn_sleep(
+        registry: &Arc<TaskRegistry>,
+        id: &'static str,
+    ) -> tokio::task::JoinHandle<Result<&'static str, Stopped>> {
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            let fut = async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                id
+            };
+            registry.run("d1", id, fut).await
+        })
+    }
+
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    #[tokio::test]
+    async fn test_finished_requests_are_forgotten() {
+        let registry = TaskRegistry::default();
+        let ret = registry.run("d1", "r1", async { 42 }).await;
+        assert_eq!(ret, Ok(42));
+        assert!(registry.running("d1").is_empty());
+        assert!(!registry.cancel("d1", "r1"));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_aborts_request() {
+        let registry = Arc::new(TaskRegistry::default());
+        let task = spawn_sleep(&registry, "r1");
+        settle().await;
+        assert_eq!(registry.running("d1"), vec!["r1".to_string()]);
+
+        // other devices can't touch it
+        assert!(!registry.cancel("d2", "r1"));
+        assert!(registry.cancel("d1", "r1"));
+        assert_eq!(task.await.unwrap(), Err(Stopped::Cancelled));
+        assert!(registry.running("d1").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_all() {
+        let registry = Arc::new(TaskRegistry::new(BargeIn::Queue));
+        let tasks = [spawn_sleep(&registry, "r1"), spawn_sleep(&registry, "r2")];
+        settle().await;
+
+        assert_eq!(registry.cancel_all("d1"), 2);
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), Err(Stopped::Cancelled));
+        }
+        assert_eq!(registry.cancel_all("d1"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_interrupt_latest() {
+        let registry = Arc::new(TaskRegistry::new(BargeIn::Interrupt));
+        let first = spawn_sleep(&registry, "r1");
+        settle().await;
+        let second = spawn_sleep(&registry, "r2");
+
+        assert_eq!(first.await.unwrap(), Err(Stopped::Interrupted));
+        assert_eq!(second.await.unwrap(), Ok("r2"));
+    }
+
+    #[tokio::test]
+    async fn test_reject_while_busy() {
+        let registry = Arc::new(TaskRegistry::new(BargeIn::Reject));
+        let first = spawn_sleep(&registry, "r1");
+        settle().await;
+        let second = spawn_sleep(&registry, "r2");
+
+        assert_eq!(second.await.unwrap(), Err(Stopped::Busy));
+        assert_eq!(first.await.unwrap(), Ok("r1"));
+        assert!(registry.running("d1").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_queue_runs_one_at_a_time() {
+        let registry = Arc::new(TaskRegistry::new(BargeIn::Queue));
+        let first = spawn_sleep(&registry, "r1");
+        settle().await;
+        let second = spawn_sleep(&registry, "r2");
+        settle().await;
+
+        // the second request waits for the first one
+        assert!(!first.is_finished());
+        let started = tokio::time::Instant::now();
+        assert_eq!(first.await.unwrap(), Ok("r1"));
+        assert_eq!(second.await.unwrap(), Ok("r2"));
+        assert!(started.elapsed() >= Duration::from_millis(300));
+        assert!(registry.queues.is_empty());
+    }
+}
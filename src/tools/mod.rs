@@ -1,5 +1,9 @@
+use crate::config::Config;
 use askama::Template;
-use llm_sdk::{ChatCompletionMessage, ChatCompletionRequest, Tool};
+use llm_sdk::{
+    ChatCompleteModel, ChatCompletionMessage, ChatCompletionRequest, ChatCompletionRequestBuilder,
+    ImageQuality, ImageSize, ImageStyle, Tool,
+};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use strum::EnumString;
@@ -16,15 +20,60 @@ pub(crate) enum AssistantTool {
     Answer,
 }
 
+// most images a single draw_image call may produce
+pub(crate) const MAX_IMAGES: usize = 4;
+
 #[derive(Debug, Clone, Deserialize, JsonSchema)]
 pub(crate) struct DrawImageArgs {
+    /// what to draw, in detail
     pub(crate) prompt: String,
+    /// square unless the user asks for a landscape or portrait picture
+    #[serde(default)]
+    pub(crate) size: DrawImageSize,
+    /// hd only when the user asks for fine details
+    #[serde(default)]
+    pub(crate) quality: DrawImageQuality,
+    #[serde(default)]
+    pub(crate) style: DrawImageStyle,
+    /// how many images to draw, between 1 and 4
+    #[serde(default = "default_count")]
+    pub(crate) count: usize,
+}
+
+// llm-sdk image options have no schema, so the tool arguments mirror them
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, JsonSchema)]
+pub(crate) enum DrawImageSize {
+    #[default]
+    #[serde(rename = "1024x1024")]
+    Square,
+    #[serde(rename = "1792x1024")]
+    Landscape,
+    #[serde(rename = "1024x1792")]
+    Portrait,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DrawImageQuality {
+    #[default]
+    Standard,
+    Hd,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DrawImageStyle {
+    #[default]
+    Vivid,
+    Natural,
 }
 
+/// one or more images drawn for the same prompt, empty `urls` while still drawing
 #[derive(Debug, Clone, Serialize, Deserialize, Template)]
 #[template(path = "blocks/image.html.jinja")]
 pub(crate) struct DrawImageResult {
-    pub(crate) url: String,
+    pub(crate) urls: Vec<String>,
     pub(crate) prompt: String,
 }
 
@@ -45,33 +94,93 @@ pub(crate) struct AnswerCodeArgs {
     pub(crate) prompt: String,
 }
 
+/// completion request letting the model pick one of `tools`, or answer directly without any
 pub(crate) fn tool_completion_request(
-    prompt: impl Into<String>,
-    name: &str,
-) -> ChatCompletionRequest {
-    let messages = vec![
-        ChatCompletionMessage::new_system("I can do to help you?", "Q"),
-        ChatCompletionMessage::new_user(prompt.into(), name),
-    ];
-    ChatCompletionRequest::new_with_tools(messages, all_tools())
+    config: &Config,
+    model: ChatCompleteModel,
+    messages: &[ChatCompletionMessage],
+    tools: Vec<Tool>,
+) -> anyhow::Result<ChatCompletionRequest> {
+    let mut all = vec![ChatCompletionMessage::new_system(
+        &config.prompts.tools,
+        &config.assistant.name,
+    )];
+    all.extend_from_slice(messages);
+
+    let req = ChatCompletionRequestBuilder::default()
+        .messages(all)
+        .tools(tools)
+        .model(model)
+        .build()?;
+    Ok(req)
+}
+
+/// plain completion request with `model`
+pub(crate) fn completion_request(
+    model: ChatCompleteModel,
+    messages: Vec<ChatCompletionMessage>,
+) -> anyhow::Result<ChatCompletionRequest> {
+    let req = ChatCompletionRequestBuilder::default()
+        .messages(messages)
+        .model(model)
+        .build()?;
+    Ok(req)
 }
 
-pub(crate) fn all_tools() -> Vec<Tool> {
+pub(crate) fn all_tools(config: &Config) -> Vec<Tool> {
+    let prompts = &config.prompts;
     vec![
-        // Tool::new_function::<DrawImageArgs>("draw_image", "Draw an image based on the prompt."),
-        Tool::new_function::<WriteCodeArgs>("write_code", "Write code based on the prompt."),
-        Tool::new_function::<AnswerCodeArgs>(
-            "answer",
-            "Just reply based on the prompt, please use simplified chinese.",
-        ),
+        Tool::new_function::<DrawImageArgs>("draw_image", &prompts.draw_image_tool),
+        Tool::new_function::<WriteCodeArgs>("write_code", &prompts.write_code_tool),
+        Tool::new_function::<AnswerCodeArgs>("answer", &prompts.answer_tool),
     ]
 }
 
 impl DrawImageResult {
-    pub fn new(url: impl Into<String>, prompt: impl Into<String>) -> Self {
+    pub fn new(urls: Vec<String>, prompt: impl Into<String>) -> Self {
         Self {
             prompt: prompt.into(),
-            url: url.into(),
+            urls,
+        }
+    }
+}
+
+impl From<DrawImageSize> for ImageSize {
+    fn from(value: DrawImageSize) -> Self {
+        match value {
+            DrawImageSize::Square => ImageSize::Large,
+            DrawImageSize::Landscape => ImageSize::LargeWide,
+            DrawImageSize::Portrait => ImageSize::LargeTall,
+        }
+    }
+}
+
+impl From<DrawImageQuality> for ImageQuality {
+    fn from(value: DrawImageQuality) -> Self {
+        match value {
+            DrawImageQuality::Standard => ImageQuality::Standard,
+            DrawImageQuality::Hd => ImageQuality::Hd,
+        }
+    }
+}
+
+impl From<DrawImageStyle> for ImageStyle {
+    fn from(value: DrawImageStyle) -> Self {
+        match value {
+            DrawImageStyle::Vivid => ImageStyle::Vivid,
+            DrawImageStyle::Natural => ImageStyle::Natural,
+        }
+    }
+}
+
+fn default_count() -> usize {
+    1
+}
+
+impl WriteCodeResult {
+    pub fn new(content: impl Into<String>) -> Self {
+        Self {
+            content: content.into(),
         }
     }
 }
@@ -0,0 +1,190 @@
+use std::{collections::BTreeMap, ops::AddAssign};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Price;
+
+/// characters of a device id shown in reports, the whole id would let anyone act as the device
+pub const DEVICE_PREFIX_LEN: usize = 8;
+
+/// what was consumed of a model, only the fields the model bills for are set
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub audio_seconds: f64,
+    pub speech_chars: u64,
+    pub images: u64,
+}
+
+/// usage of one device with one model on one day (UTC)
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageRecord {
+    pub device_id: String,
+    pub day: String,
+    pub model: String,
+    pub usage: Usage,
+    /// estimated when the usage was recorded, the caps add up the same figure
+    pub cost: f64,
+}
+
+/// usage since `since`, with the devices costing the most first
+#[derive(Debug, Clone, Serialize)]
+pub struct UsageReport {
+    pub since: String,
+    pub cost: f64,
+    pub devices: Vec<DeviceUsage>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceUsage {
+    /// the first `DEVICE_PREFIX_LEN` characters of the device id
+    pub device: String,
+    pub cost: f64,
+    /// by day, then model
+    pub usage: Vec<ModelUsage>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelUsage {
+    pub day: String,
+    pub model: String,
+    #[serde(flatten)]
+    pub usage: Usage,
+    pub cost: f64,
+}
+
+impl Usage {
+    pub fn tokens(prompt: u64, completion: u64) -> Self {
+        Self {
+            prompt_tokens: prompt,
+            completion_tokens: completion,
+            ..Default::default()
+        }
+    }
+
+    pub fn audio(seconds: f64) -> Self {
+        Self {
+            audio_seconds: seconds,
+            ..Default::default()
+        }
+    }
+
+    pub fn speech(chars: u64) -> Self {
+        Self {
+            speech_chars: chars,
+            ..Default::default()
+        }
+    }
+
+    pub fn images(count: u64) -> Self {
+        Self {
+            images: count,
+            ..Default::default()
+        }
+    }
+
+    /// prompt and completion tokens together
+    pub fn total_tokens(&self) -> u64 {
+        self.prompt_tokens + self.completion_tokens
+    }
+
+    /// estimated cost in USD
+    pub fn cost(&self, price: &Price) -> f64 {
+        self.prompt_tokens as f64 / 1000.0 * price.prompt
+            + self.completion_tokens as f64 / 1000.0 * price.completion
+            + self.audio_seconds / 60.0 * price.minute
+            + self.speech_chars as f64 / 1000.0 * price.chars
+            + self.images as f64 * price.image
+    }
+}
+
+impl AddAssign for Usage {
+    fn add_assign(&mut self, rhs: Self) {
+        self.prompt_tokens += rhs.prompt_tokens;
+        self.completion_tokens += rhs.completion_tokens;
+        self.audio_seconds += rhs.audio_seconds;
+        self.speech_chars += rhs.speech_chars;
+        self.images += rhs.images;
+    }
+}
+
+impl UsageReport {
+    pub fn new(since: impl Into<String>, records: Vec<UsageRecord>) -> Self {
+        let mut devices: BTreeMap<String, DeviceUsage> = BTreeMap::new();
+        for v in records {
+            let cost = v.cost;
+            let device = devices
+                .entry(v.device_id.clone())
+                .or_insert_with(|| DeviceUsage {
+                    device: v.device_id.chars().take(DEVICE_PREFIX_LEN).collect(),
+                    cost: 0.0,
+                    usage: vec![],
+                });
+            device.cost += cost;
+            device.usage.push(ModelUsage {
+                day: v.day,
+                model: v.model,
+                usage: v.usage,
+                cost,
+            });
+        }
+
+        let mut devices: Vec<_> = devices.into_values().collect();
+        for device in &mut devices {
+            device
+                .usage
+                .sort_by(|a, b| (&a.day, &a.model).cmp(&(&b.day, &b.model)));
+        }
+        devices.sort_by(|a, b| b.cost.total_cmp(&a.cost));
+
+        Self {
+            since: since.into(),
+            cost: devices.iter().map(|v| v.cost).sum(),
+            devices,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Pricing;
+
+    fn record(device_id: &str, day: &str, model: &str, usage: Usage) -> UsageRecord {
+        UsageRecord {
+            device_id: device_id.into(),
+            day: day.into(),
+            model: model.into(),
+            usage,
+            cost: usage.cost(&Pricing::default().get(model)),
+        }
+    }
+
+    #[test]
+    fn test_cost() {
+        let pricing = Pricing::default();
+        let cost = Usage::tokens(1000, 500).cost(&pricing.get("gpt-3.5-turbo-1106"));
+        assert!((cost - 0.002).abs() < 1e-9);
+        let cost = Usage::audio(90.0).cost(&pricing.get("whisper-1"));
+        assert!((cost - 0.009).abs() < 1e-9);
+        assert_eq!(Usage::images(2).cost(&pricing.get("unknown")), 0.0);
+    }
+
+    #[test]
+    fn test_report_puts_the_costliest_device_first() {
+        let report = UsageReport::new(
+            "2023-12-01",
+            vec![
+                record("cheap", "2023-12-01", "whisper-1", Usage::audio(60.0)),
+                record("pricey-device", "2023-12-02", "dall-e-3", Usage::images(1)),
+                record("pricey-device", "2023-12-01", "dall-e-3", Usage::images(2)),
+            ],
+        );
+
+        let ids: Vec<_> = report.devices.iter().map(|v| v.device.as_str()).collect();
+        assert_eq!(ids, vec!["pricey-d", "cheap"]);
+        assert_eq!(report.devices[0].usage[0].day, "2023-12-01");
+        assert!((report.cost - 0.126).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,149 @@
+mod common;
+
+use anyhow::Result;
+use axum::{body::Body, http::Request};
+use common::{read_events, TestApp};
+use q_bot::llm::FakeBackend;
+use serde_json::json;
+use uuid::Uuid;
+
+async fn get(app: &TestApp, uri: &str) -> Result<(u16, String)> {
+    let req = Request::get(uri)
+        .header("cookie", app.cookie())
+        .body(Body::empty())?;
+    let res = app.request(req).await?;
+    let status = res.status().as_u16();
+    let body = hyper::body::to_bytes(res.into_body()).await?;
+
+    Ok((status, String::from_utf8(body.to_vec())?))
+}
+
+#[tokio::test]
+async fn deleting_the_conversation_removes_its_files() -> Result<()> {
+    let llm = FakeBackend::default()
+        .with_transcript("my name is zheng")
+        .with_reply("nice to meet you")
+        .with_transcript("what is my name")
+        .with_reply("no idea");
+    let app = TestApp::with_args(llm, &["--speech-cache-mb", "0"])?;
+
+    let mut body = app.events().await?;
+    app.assist(b"voice").await?;
+    let events = read_events(&mut body).await?;
+    let spoken = &events.last().unwrap().data;
+    let url = spoken
+        .split("src='")
+        .nth(1)
+        .unwrap()
+        .split('\'')
+        .next()
+        .unwrap();
+    assert_eq!(get(&app, url).await?.0, 200);
+
+    let req = Request::delete("/chats")
+        .header("cookie", app.cookie())
+        .body(Body::empty())?;
+    let res = app.request(req).await?;
+    assert_eq!(res.status(), 200);
+
+    assert_eq!(get(&app, url).await?.0, 404);
+    let (_, history) = get(&app, "/chats").await?;
+    assert!(!history.contains("my name is zheng"));
+
+    // and Q forgot about it too
+    app.assist(b"voice").await?;
+    let requests = app.llm.chat_requests();
+    let messages = requests[1]["messages"].as_array().unwrap();
+    assert!(!messages.iter().any(|v| v["content"] == "my name is zheng"));
+    Ok(())
+}
+
+// the url of the audio in the last spoken reply
+async fn spoken_url(app: &TestApp, text: &str) -> Result<String> {
+    let mut body = app.events().await?;
+    app.ask(json!({ "text": text, "speech": true })).await?;
+    let events = read_events(&mut body).await?;
+    let spoken = &events.last().unwrap().data;
+    let url = spoken.split("src='").nth(1).unwrap().split('\'').next();
+
+    Ok(url.unwrap().to_string())
+}
+
+fn other_device(app: &TestApp) -> TestApp {
+    TestApp {
+        device_id: Uuid::new_v4().to_string(),
+        ..app.clone()
+    }
+}
+
+#[tokio::test]
+async fn device_files_are_private() -> Result<()> {
+    let llm = FakeBackend::default().with_reply("a secret");
+    let app = TestApp::with_args(llm, &["--speech-cache-mb", "0"])?;
+    let url = spoken_url(&app, "tell me a secret").await?;
+    assert!(url.starts_with(&format!("/assets/audio/{}/", app.device_id)));
+
+    assert_eq!(get(&app, &url).await?.0, 200);
+    assert_eq!(get(&other_device(&app), &url).await?.0, 404);
+    // no way out of the asset directory either
+    let escape = format!("/assets/audio/{}/..%2F..%2Fqbot.db", app.device_id);
+    assert_eq!(get(&app, &escape).await?.0, 404);
+    Ok(())
+}
+
+#[tokio::test]
+async fn forged_device_ids_delete_nothing() -> Result<()> {
+    let llm = FakeBackend::default().with_reply("keep this");
+    let app = TestApp::with_args(llm, &["--speech-cache-mb", "0"])?;
+    let url = spoken_url(&app, "hi").await?;
+
+    for forged in ["..", "../..", "audio"] {
+        let req = Request::delete("/chats")
+            .header("cookie", format!("device_id={}", forged))
+            .body(Body::empty())?;
+        let res = app.request(req).await?;
+        assert_eq!(res.status(), 400);
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        let res: serde_json::Value = serde_json::from_slice(&body)?;
+        assert_eq!(res["code"], "bad_input");
+    }
+
+    assert_eq!(get(&app, &url).await?.0, 200);
+    let (_, history) = get(&app, "/chats").await?;
+    assert!(history.contains("keep this"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn cached_speech_is_served_to_those_who_heard_it() -> Result<()> {
+    let llm = FakeBackend::default()
+        .with_reply("hello there")
+        .with_reply("hello there");
+    let app = TestApp::new(llm)?;
+    let url = spoken_url(&app, "hi").await?;
+    assert!(url.starts_with("/assets/speech/"));
+    assert_eq!(get(&app, &url).await?.0, 200);
+
+    let other = other_device(&app);
+    assert_eq!(get(&other, &url).await?.0, 404);
+    // until it is spoken to them too
+    assert_eq!(spoken_url(&other, "hi").await?, url);
+    assert_eq!(get(&other, &url).await?.0, 200);
+    Ok(())
+}
+
+#[tokio::test]
+async fn metrics_report_disk_usage() -> Result<()> {
+    let llm = FakeBackend::default()
+        .with_transcript("hi")
+        .with_reply("hello there");
+    let app = TestApp::new(llm)?;
+    app.ask(json!({ "text": "hi", "speech": true })).await?;
+
+    let (status, metrics) = get(&app, "/metrics").await?;
+    assert_eq!(status, 200);
+    assert!(metrics.contains("qbot_asset_bytes{kind=\"audio\"} 0"));
+    assert!(metrics.contains("qbot_asset_bytes{kind=\"speech\"} 8"));
+    assert!(metrics.contains("qbot_asset_swept_files_total 0"));
+    Ok(())
+}
@@ -0,0 +1,433 @@
+mod common;
+
+use std::time::Duration;
+
+use anyhow::Result;
+use common::{names, read_events, signals, TestApp};
+use hyper::body::HttpBody;
+use q_bot::llm::FakeBackend;
+use serde_json::json;
+
+#[tokio::test]
+async fn answer_tool_streams_reply_then_speech() -> Result<()> {
+    let llm = FakeBackend::default()
+        .with_transcript("what is rust")
+        .with_tool_call("answer", json!({ "prompt": "what is rust" }))
+        .with_stream(["Rust is ", "a language"]);
+    let app = TestApp::new(llm)?;
+
+    let mut body = app.events().await?;
+    let (status, res) = app.assist(b"voice").await?;
+    assert_eq!(status, 200);
+    assert_eq!(res, json!({ "status": "done" }));
+
+    let events = read_events(&mut body).await?;
+    assert_eq!(
+        names(&events),
+        vec![
+            "signal",
+            "signal",
+            "input_skeleton",
+            "input",
+            "signal",
+            "reply_skeleton",
+            "signal",
+            "signal",
+            "reply",
+            "signal",
+            "signal",
+            "reply",
+        ]
+    );
+    assert_eq!(
+        signals(&events),
+        vec![
+            "Processing upload_audio",
+            "Processing transcription",
+            "Processing thinking",
+            "Processing chat_completion",
+            "Complete",
+            "Processing speech",
+            "Complete",
+        ]
+    );
+
+    let id = events[2].block();
+    assert!(!id.is_empty());
+    assert!(events[3].data.contains("what is rust"));
+    assert_eq!(events[5].block(), id);
+    assert!(events[8].data.contains("Rust is a language"));
+    assert!(!events[8].data.contains("<audio"));
+    assert!(events[11].data.contains("<audio"));
+    assert!(events[11].data.contains("/assets/speech/"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn direct_reply_skips_tools() -> Result<()> {
+    let llm = FakeBackend::default()
+        .with_transcript("hi")
+        .with_reply("hello there");
+    let app = TestApp::new(llm)?;
+
+    let mut body = app.events().await?;
+    app.assist(b"voice").await?;
+
+    let events = read_events(&mut body).await?;
+    assert_eq!(
+        names(&events),
+        vec![
+            "signal",
+            "signal",
+            "input_skeleton",
+            "input",
+            "signal",
+            "reply_skeleton",
+            "signal",
+            "reply",
+            "signal",
+            "reply",
+        ]
+    );
+    assert!(events[7].data.contains("hello there"));
+    assert!(events[9].data.contains("<audio"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn write_code_renders_markdown() -> Result<()> {
+    let llm = FakeBackend::default()
+        .with_transcript("write hello world in rust")
+        .with_tool_call("write_code", json!({ "prompt": "hello world in rust" }))
+        .with_stream(["```rust\n", "fn main() {}\n", "```\n"])
+        .with_reply("here is your hello world");
+    let app = TestApp::new(llm)?;
+
+    let mut body = app.events().await?;
+    app.assist(b"voice").await?;
+
+    let events = read_events(&mut body).await?;
+    assert_eq!(
+        signals(&events),
+        vec![
+            "Processing upload_audio",
+            "Processing transcription",
+            "Processing thinking",
+            "Processing write_code",
+            "Complete",
+            "Processing speech",
+            "Complete",
+        ]
+    );
+    let id = events[2].block();
+    let code = events
+        .iter()
+        .rfind(|v| v.event == "reply" && v.block() == id)
+        .unwrap();
+    assert!(code.data.contains("<pre"));
+    assert!(code.data.contains("main"));
+
+    // the summary is spoken in a block of its own
+    let summary = events.last().unwrap();
+    assert_eq!(summary.event, "reply");
+    assert_eq!(summary.block(), format!("{}-1", id));
+    assert!(summary.data.contains("here is your hello world"));
+    assert!(summary.data.contains("<audio"));
+
+    // and the model saw the code before summarizing
+    let requests = app.llm.chat_requests();
+    assert_eq!(requests.len(), 3);
+    let last = requests[2]["messages"].as_array().unwrap().last().unwrap();
+    assert_eq!(last["name"], "write_code");
+    assert!(last["content"].as_str().unwrap().contains("fn main()"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn parallel_tool_calls_each_get_a_block() -> Result<()> {
+    let llm = FakeBackend::default()
+        .with_transcript("explain and show rust")
+        .with_tool_calls([
+            ("answer", json!({ "prompt": "what is rust" })),
+            ("write_code", json!({ "prompt": "hello world in rust" })),
+        ])
+        .with_stream(["Rust is a language"])
+        .with_stream(["```rust\nfn main() {}\n```\n"])
+        .with_reply("that is rust");
+    let app = TestApp::new(llm)?;
+
+    let mut body = app.events().await?;
+    let (_, res) = app.assist(b"voice").await?;
+    assert_eq!(res, json!({ "status": "done" }));
+
+    let events = read_events(&mut body).await?;
+    let id = events[2].block();
+    let skeletons: Vec<_> = events
+        .iter()
+        .filter(|v| v.event == "reply_skeleton")
+        .map(|v| v.block().to_string())
+        .collect();
+    assert_eq!(
+        skeletons,
+        vec![id.to_string(), format!("{}-1", id), format!("{}-2", id)]
+    );
+    for block in &skeletons {
+        assert!(events
+            .iter()
+            .any(|v| v.event == "reply" && v.block() == block));
+    }
+    assert!(events.last().unwrap().data.contains("that is rust"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn tool_loop_stops_after_max_rounds() -> Result<()> {
+    let llm = FakeBackend::default()
+        .with_transcript("code forever")
+        .with_tool_call("write_code", json!({ "prompt": "one" }))
+        .with_stream(["one"])
+        .with_tool_call("write_code", json!({ "prompt": "two" }))
+        .with_stream(["two"])
+        .with_reply("enough");
+    let app = TestApp::new(llm)?;
+
+    let mut body = app.events().await?;
+    let (_, res) = app.assist(b"voice").await?;
+    assert_eq!(res, json!({ "status": "done" }));
+
+    let requests = app.llm.chat_requests();
+    let rounds: Vec<_> = requests
+        .iter()
+        // the tool picking requests, the streamed tool replies use their own prompts
+        .filter(|v| v["messages"][0]["content"] == "I can do to help you?")
+        .map(|v| v.get("tools").is_some())
+        .collect();
+    assert_eq!(rounds, vec![true, true, false]);
+
+    let events = read_events(&mut body).await?;
+    assert!(events.last().unwrap().data.contains("enough"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn missing_audio_field_signals_error() -> Result<()> {
+    let app = TestApp::new(FakeBackend::default())?;
+
+    let mut body = app.events().await?;
+    let req = axum::http::Request::post("/assistant")
+        .header("cookie", app.cookie())
+        .header(
+            "content-type",
+            "multipart/form-data; boundary=qbot-test-boundary",
+        )
+        .body(common::multipart("text", b"hello").into())?;
+    let res = app.request(req).await?;
+    assert_eq!(res.status(), 400);
+
+    let events = read_events(&mut body).await?;
+    assert_eq!(
+        signals(&events),
+        vec!["Processing upload_audio", "Error expected an audio field"]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn follow_up_sees_previous_turn() -> Result<()> {
+    let llm = FakeBackend::default()
+        .with_transcript("my name is zheng")
+        .with_reply("nice to meet you")
+        .with_transcript("what is my name")
+        .with_reply("zheng");
+    let app = TestApp::new(llm)?;
+
+    let _body = app.events().await?;
+    app.assist(b"voice").await?;
+    app.assist(b"voice").await?;
+
+    let requests = app.llm.chat_requests();
+    assert_eq!(requests.len(), 2);
+    let messages = requests[1]["messages"].as_array().unwrap();
+    let contents: Vec<_> = messages.iter().map(|v| v["content"].as_str()).collect();
+    assert_eq!(
+        contents[1..],
+        [
+            Some("my name is zheng"),
+            Some("nice to meet you"),
+            Some("what is my name")
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn long_replies_are_spoken_as_a_playlist() -> Result<()> {
+    let reply =
+        "Rust is a systems language. 它保证内存安全，而且没有垃圾回收！Give it a try today.";
+    let llm = FakeBackend::default()
+        .with_transcript("tell me about rust")
+        .with_reply(reply);
+    // without the cache every segment is a file of the device
+    let app = TestApp::with_args(llm, &["--speech-cache-mb", "0"])?;
+
+    let mut body = app.events().await?;
+    app.assist(b"voice").await?;
+
+    let events = read_events(&mut body).await?;
+    let spoken = events.last().unwrap();
+    let playlist = spoken
+        .data
+        .split("data-playlist=\"")
+        .nth(1)
+        .and_then(|v| v.split('"').next())
+        .unwrap();
+    let urls: Vec<_> = playlist.split(' ').collect();
+    assert_eq!(urls.len(), 3);
+    assert!(urls
+        .iter()
+        .all(|v| v.starts_with(&format!("/assets/audio/{}/", app.device_id))));
+    assert!(spoken.data.contains(&format!("src='{}'", urls[0])));
+
+    let mut inputs = app.llm.speech_inputs();
+    inputs.sort();
+    assert_eq!(
+        inputs,
+        vec![
+            "Give it a try today.",
+            "Rust is a systems language.",
+            "它保证内存安全，而且没有垃圾回收！"
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn repeated_phrases_come_from_the_speech_cache() -> Result<()> {
+    let reply = "Hello there, nice to see you.";
+    let llm = FakeBackend::default()
+        .with_transcript("hi")
+        .with_reply(reply)
+        .with_transcript("hi again")
+        .with_reply(reply);
+    let app = TestApp::new(llm)?;
+
+    let mut body = app.events().await?;
+    app.assist(b"voice").await?;
+    app.assist(b"voice").await?;
+
+    let events = read_events(&mut body).await?;
+    let spoken: Vec<_> = events
+        .iter()
+        .filter(|v| v.event == "reply" && v.data.contains("<audio"))
+        .collect();
+    assert_eq!(spoken.len(), 2);
+    let url = |data: &str| {
+        data.split("src='")
+            .nth(1)
+            .unwrap()
+            .split('\'')
+            .next()
+            .unwrap()
+            .to_string()
+    };
+    assert_eq!(url(&spoken[0].data), url(&spoken[1].data));
+    assert!(url(&spoken[0].data).starts_with("/assets/speech/"));
+
+    assert_eq!(app.llm.speech_inputs(), vec![reply]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn upstream_failures_are_not_leaked() -> Result<()> {
+    // nothing scripted past the transcript, so the model call fails
+    let app = TestApp::new(FakeBackend::default().with_transcript("hi"))?;
+
+    let mut body = app.events().await?;
+    let (status, res) = app.assist(b"voice").await?;
+    assert_eq!(status, 502);
+    assert_eq!(res["code"], "upstream");
+    assert!(!res["message"].as_str().unwrap().contains("scripted"));
+
+    let events = read_events(&mut body).await?;
+    let signal = &events.last().unwrap().data;
+    assert!(signal.contains("the AI service failed"));
+    assert!(!signal.contains("scripted"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn missing_cookie_is_a_bad_request() -> Result<()> {
+    let app = TestApp::new(FakeBackend::default())?;
+
+    let req = axum::http::Request::post("/assistant/text")
+        .header("content-type", "application/json")
+        .body(r#"{"text":"hi"}"#.into())?;
+    let res = app.request(req).await?;
+    assert_eq!(res.status(), 400);
+    let body = hyper::body::to_bytes(res.into_body()).await?;
+    let res: serde_json::Value = serde_json::from_slice(&body)?;
+    assert_eq!(res["code"], "missing_cookie");
+    Ok(())
+}
+
+#[tokio::test]
+async fn malformed_tool_arguments_signal_error() -> Result<()> {
+    let llm = FakeBackend::default()
+        .with_transcript("draw a cat")
+        .with_tool_call("draw_image", json!("not an object"));
+    let app = TestApp::new(llm)?;
+
+    let mut body = app.events().await?;
+    let (status, res) = app.assist(b"voice").await?;
+    assert_eq!((status, &res["code"]), (502, &json!("upstream")));
+
+    let events = read_events(&mut body).await?;
+    assert!(events
+        .last()
+        .unwrap()
+        .data
+        .contains("the AI service failed"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn speech_starts_before_the_last_segment_is_done() -> Result<()> {
+    let reply = "This is the first one. This is the second one. This is the third one. \
+        This is the fourth one. This is the fifth one. This is the sixth one.";
+    let llm = FakeBackend::default()
+        .with_latency(Duration::from_millis(100))
+        .with_transcript("count to six")
+        .with_reply(reply);
+    let app = TestApp::with_args(llm, &["--speech-cache-mb", "0"])?;
+
+    let mut body = app.events().await?;
+    // what was synthesized when the first audio reached the browser
+    let first_audio = async {
+        let mut buf = String::new();
+        while let Some(chunk) = body.data().await {
+            buf.push_str(std::str::from_utf8(&chunk?)?);
+            if buf.contains("<audio") {
+                return anyhow::Ok(app.llm.speech_inputs().len());
+            }
+        }
+        anyhow::bail!("no audio was sent")
+    };
+    let (synthesized, res) = tokio::join!(first_audio, app.assist(b"voice"));
+    assert_eq!(res?.0, 200);
+    assert!(synthesized? < 6);
+
+    // later segments extend the playlist, the stored reply has all of them
+    let events = read_events(&mut body).await?;
+    let playlist = events.last().unwrap().data.split("data-playlist=\"").nth(1);
+    assert_eq!(
+        playlist
+            .unwrap()
+            .split('"')
+            .next()
+            .unwrap()
+            .split(' ')
+            .count(),
+        6
+    );
+    Ok(())
+}
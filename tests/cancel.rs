@@ -0,0 +1,107 @@
+mod common;
+
+use std::time::Duration;
+
+use anyhow::Result;
+use common::{names, read_events, TestApp};
+use q_bot::llm::FakeBackend;
+use serde_json::json;
+use uuid::Uuid;
+
+fn slow_app() -> Result<TestApp> {
+    let llm = FakeBackend::default()
+        .with_latency(Duration::from_millis(500))
+        .with_transcript("oops, wrong question")
+        .with_reply("an expensive answer");
+    TestApp::new(llm)
+}
+
+#[tokio::test]
+async fn cancel_stops_the_remaining_steps() -> Result<()> {
+    let app = slow_app()?;
+    let mut body = app.events().await?;
+    let (ret, id) = tokio::join!(app.assist(b"voice"), async {
+        // the request is being transcribed
+        let events = read_events(&mut body).await?;
+        assert_eq!(names(&events), vec!["signal", "signal", "input_skeleton"]);
+        let id = events[2].block().to_string();
+
+        let (status, res) = app.cancel(&id).await?;
+        assert_eq!(status, 200);
+        assert_eq!(res, json!({ "status": "cancelled" }));
+        anyhow::Ok(id)
+    });
+    let id = id?;
+    assert_eq!(ret?.1, json!({ "status": "cancelled" }));
+
+    let events = read_events(&mut body).await?;
+    assert_eq!(names(&events), vec!["signal"]);
+    assert!(events[0].data.contains("Cancelled"));
+
+    // nothing else was asked of the model
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    assert!(app.llm.chat_requests().is_empty());
+
+    // and the request is gone
+    let (status, _) = app.cancel(&id).await?;
+    assert_eq!(status, 404);
+    Ok(())
+}
+
+#[tokio::test]
+async fn cancel_is_scoped_to_the_device() -> Result<()> {
+    let app = slow_app()?;
+    let other = TestApp {
+        device_id: Uuid::new_v4().to_string(),
+        ..app.clone()
+    };
+
+    let mut body = app.events().await?;
+    let (ret, cancel) = tokio::join!(app.assist(b"voice"), async {
+        let events = read_events(&mut body).await?;
+        other.cancel(events[2].block()).await
+    });
+    let (status, res) = cancel?;
+    assert_eq!((status, &res["code"]), (404, &json!("not_found")));
+    assert_eq!(ret?.1, json!({ "status": "done" }));
+    Ok(())
+}
+
+#[tokio::test]
+async fn new_question_interrupts_the_running_one() -> Result<()> {
+    let llm = FakeBackend::default()
+        .with_latency(Duration::from_millis(300))
+        .with_reply("the second answer");
+    let app = TestApp::new(llm)?;
+
+    let mut body = app.events().await?;
+    let (first, second) = tokio::join!(app.ask(json!({ "text": "first" })), async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        app.ask(json!({ "text": "second" })).await
+    });
+    assert_eq!(first?.1, json!({ "status": "interrupted" }));
+    assert_eq!(second?.1, json!({ "status": "done" }));
+
+    let events = read_events(&mut body).await?;
+    assert!(events.iter().any(|v| v.data.contains("Interrupted")));
+    assert!(events.last().unwrap().data.contains("the second answer"));
+    assert_eq!(app.llm.chat_requests().len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn reject_turns_down_new_questions_while_busy() -> Result<()> {
+    let llm = FakeBackend::default()
+        .with_latency(Duration::from_millis(300))
+        .with_reply("the first answer");
+    let app = TestApp::with_args(llm, &["--barge-in", "reject"])?;
+
+    let (first, second) = tokio::join!(app.ask(json!({ "text": "first" })), async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        app.ask(json!({ "text": "second" })).await
+    });
+    assert_eq!(first?.1, json!({ "status": "done" }));
+    let (status, res) = second?;
+    assert_eq!((status, &res["code"]), (409, &json!("busy")));
+    Ok(())
+}
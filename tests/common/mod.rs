@@ -0,0 +1,231 @@
+#![allow(dead_code)]
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use axum::{
+    body::{Body, BoxBody, HttpBody},
+    http::{header, Request, Response},
+    Router,
+};
+use clap::Parser;
+use q_bot::{app, llm::FakeBackend, AppState, Args};
+use serde_json::Value;
+use tower::ServiceExt;
+use uuid::Uuid;
+
+const BOUNDARY: &str = "qbot-test-boundary";
+
+/// a server-sent event as a browser would see it
+#[derive(Debug, Clone, Default)]
+pub struct SseEvent {
+    pub event: String,
+    pub id: String,
+    pub data: String,
+}
+
+impl SseEvent {
+    /// request id of the chat block the event creates or updates
+    pub fn block(&self) -> &str {
+        let Some(start) = self
+            .data
+            .find("id=\"input-")
+            .or(self.data.find("id=\"reply-"))
+        else {
+            return "";
+        };
+        let rest = &self.data[start + "id=\"input-".len()..];
+        rest.split('"').next().unwrap_or_default()
+    }
+}
+
+#[derive(Clone)]
+pub struct TestApp {
+    pub app: Router,
+    pub llm: Arc<FakeBackend>,
+    pub device_id: String,
+}
+
+impl TestApp {
+    pub fn new(llm: FakeBackend) -> Result<Self> {
+        Self::with_args(llm, &[])
+    }
+
+    /// like `new`, with extra command line flags
+    pub fn with_args(llm: FakeBackend, extra: &[&str]) -> Result<Self> {
+        // every app writes its files to a directory of its own
+        let asset_dir = std::env::temp_dir().join(format!("qbot-test-{}", Uuid::new_v4()));
+        let asset_dir = asset_dir.to_string_lossy();
+        // retries back off for a millisecond, not long enough to slow the tests down
+        let mut argv = vec![
+            "qbot",
+            "--database",
+            ":memory:",
+            "--asset-dir",
+            &asset_dir,
+            "--llm-backoff-ms",
+            "1",
+        ];
+        argv.extend_from_slice(extra);
+        let args = Args::parse_from(argv);
+        let llm = Arc::new(llm);
+        let state = AppState::with_backend(&args, Box::new(llm.clone()))?;
+
+        Ok(Self {
+            app: app(Arc::new(state)),
+            llm,
+            device_id: Uuid::new_v4().to_string(),
+        })
+    }
+
+    pub async fn request(&self, req: Request<Body>) -> Result<Response<BoxBody>> {
+        Ok(self.app.clone().oneshot(req).await?)
+    }
+
+    /// open `/events`, the body yields the event stream
+    pub async fn events(&self) -> Result<BoxBody> {
+        let req = Request::get("/events")
+            .header(header::COOKIE, self.cookie())
+            .body(Body::empty())?;
+
+        Ok(self.request(req).await?.into_body())
+    }
+
+    /// reconnect to `/events` the way a browser does after losing the connection
+    pub async fn reconnect(&self, last_id: &str) -> Result<BoxBody> {
+        let req = Request::get("/events")
+            .header(header::COOKIE, self.cookie())
+            .header("last-event-id", last_id)
+            .body(Body::empty())?;
+
+        Ok(self.request(req).await?.into_body())
+    }
+
+    /// post a voice recording to `/assistant`
+    pub async fn assist(&self, audio: &[u8]) -> Result<(u16, Value)> {
+        let req = Request::post("/assistant")
+            .header(header::COOKIE, self.cookie())
+            .header(
+                header::CONTENT_TYPE,
+                format!("multipart/form-data; boundary={}", BOUNDARY),
+            )
+            .body(Body::from(multipart("audio", audio)))?;
+
+        let res = self.request(req).await?;
+        let status = res.status().as_u16();
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+
+        Ok((status, serde_json::from_slice(&body)?))
+    }
+
+    /// post a typed question to `/assistant/text`
+    pub async fn ask(&self, body: Value) -> Result<(u16, Value)> {
+        let req = Request::post("/assistant/text")
+            .header(header::COOKIE, self.cookie())
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))?;
+
+        let res = self.request(req).await?;
+        let status = res.status().as_u16();
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+
+        Ok((status, serde_json::from_slice(&body)?))
+    }
+
+    /// cancel a request with `DELETE /assistant/{id}`
+    pub async fn cancel(&self, id: &str) -> Result<(u16, Value)> {
+        let req = Request::delete(format!("/assistant/{}", id))
+            .header(header::COOKIE, self.cookie())
+            .body(Body::empty())?;
+
+        let res = self.request(req).await?;
+        let status = res.status().as_u16();
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+
+        Ok((status, serde_json::from_slice(&body)?))
+    }
+
+    pub fn cookie(&self) -> String {
+        format!("device_id={}", self.device_id)
+    }
+}
+
+pub fn multipart(name: &str, data: &[u8]) -> Vec<u8> {
+    let mut body = format!(
+        "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{name}\"; filename=\"blob\"\r\nContent-Type: audio/mp3\r\n\r\n"
+    )
+    .into_bytes();
+    body.extend_from_slice(data);
+    body.extend_from_slice(format!("\r\n--{BOUNDARY}--\r\n").as_bytes());
+    body
+}
+
+/// read events until the stream has been quiet for a while, keep-alives are skipped
+pub async fn read_events(body: &mut BoxBody) -> Result<Vec<SseEvent>> {
+    let mut buf = String::new();
+    let mut events = vec![];
+    while let Ok(Some(chunk)) = tokio::time::timeout(Duration::from_millis(300), body.data()).await
+    {
+        buf.push_str(std::str::from_utf8(&chunk?)?);
+        while let Some(pos) = buf.find("\n\n") {
+            let frame: String = buf.drain(..pos + 2).collect();
+            if let Some(event) = parse_frame(&frame) {
+                events.push(event);
+            }
+        }
+    }
+
+    Ok(events)
+}
+
+pub fn names(events: &[SseEvent]) -> Vec<&str> {
+    events.iter().map(|v| v.event.as_str()).collect()
+}
+
+// signals render as `<p class='...'>  Processing thinking </p>`, keep only the text
+pub fn signals(events: &[SseEvent]) -> Vec<String> {
+    events
+        .iter()
+        .filter(|v| v.event == "signal")
+        .map(|v| {
+            let text = v.data.split('>').nth(1).unwrap_or_default();
+            text.split('<')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_string()
+        })
+        .collect()
+}
+
+fn parse_frame(frame: &str) -> Option<SseEvent> {
+    let mut event = SseEvent::default();
+    let mut data = vec![];
+    for line in frame.lines() {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.strip_prefix(' ').unwrap_or(value);
+        match name {
+            "event" => event.event = value.to_string(),
+            "id" => event.id = value.to_string(),
+            "data" => data.push(value),
+            _ => {}
+        }
+    }
+    event.data = data.join("\n");
+
+    (!event.event.is_empty()).then_some(event)
+}
+
+impl TestApp {
+    /// serve the app on a local port, websockets need a real connection
+    pub async fn serve(&self) -> Result<std::net::SocketAddr> {
+        let server =
+            axum::Server::bind(&"127.0.0.1:0".parse()?).serve(self.app.clone().into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        Ok(addr)
+    }
+}
@@ -0,0 +1,168 @@
+mod common;
+
+use std::time::Duration;
+
+use anyhow::Result;
+use axum::{body::Body, http::Request};
+use common::{read_events, TestApp};
+use q_bot::llm::FakeBackend;
+use serde_json::{json, Value};
+
+#[tokio::test]
+async fn draw_image_renders_a_gallery() -> Result<()> {
+    let llm = FakeBackend::default()
+        .with_transcript("draw two cats")
+        .with_tool_call(
+            "draw_image",
+            json!({ "prompt": "a cat", "size": "1792x1024", "style": "natural", "count": 2 }),
+        )
+        .with_reply("here are your cats");
+    let app = TestApp::new(llm)?;
+
+    let mut body = app.events().await?;
+    let (_, res) = app.assist(b"voice").await?;
+    assert_eq!(res, json!({ "status": "done" }));
+
+    let events = read_events(&mut body).await?;
+    let id = events[2].block();
+    let gallery = events
+        .iter()
+        .rfind(|v| v.event == "reply" && v.block() == id)
+        .unwrap();
+    let prefix = format!("/assets/image/{}/", app.device_id);
+    assert_eq!(
+        gallery
+            .data
+            .matches(&format!("<img src='{}", prefix))
+            .count(),
+        2
+    );
+    assert!(gallery.data.contains("a cat"));
+    assert!(events.last().unwrap().data.contains("here are your cats"));
+
+    // the schema offers the options and the model sees the revised prompt
+    let requests = app.llm.chat_requests();
+    let tools = requests[0]["tools"].to_string();
+    assert!(tools.contains("draw_image"));
+    assert!(tools.contains("1792x1024"));
+    let last = requests[1]["messages"].as_array().unwrap().last().unwrap();
+    assert_eq!(last["name"], "draw_image");
+    assert_eq!(last["content"], "a cat");
+    Ok(())
+}
+
+#[tokio::test]
+async fn draw_image_respects_daily_quota() -> Result<()> {
+    let llm = FakeBackend::default()
+        .with_transcript("draw three cats")
+        .with_tool_call("draw_image", json!({ "prompt": "a cat", "count": 3 }))
+        .with_reply("here are your cats")
+        .with_transcript("one more")
+        .with_tool_call("draw_image", json!({ "prompt": "a dog" }));
+    let app = TestApp::with_args(llm, &["--image-quota", "2"])?;
+
+    let mut body = app.events().await?;
+    let (_, res) = app.assist(b"voice").await?;
+    assert_eq!(res, json!({ "status": "done" }));
+
+    // only what is left of the quota gets drawn
+    let events = read_events(&mut body).await?;
+    let id = events[2].block();
+    let gallery = events
+        .iter()
+        .rfind(|v| v.event == "reply" && v.block() == id)
+        .unwrap();
+    assert_eq!(gallery.data.matches("<img").count(), 2);
+
+    let (status, res) = app.assist(b"voice").await?;
+    assert_eq!(status, 429);
+    assert_eq!(res["code"], "rate_limited");
+    let events = read_events(&mut body).await?;
+    assert!(events
+        .last()
+        .unwrap()
+        .data
+        .contains("daily image quota of 2 reached"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn parallel_draw_calls_share_the_quota() -> Result<()> {
+    let llm = FakeBackend::default()
+        .with_latency(Duration::from_millis(20))
+        .with_transcript("draw cats and dogs")
+        .with_tool_calls([
+            ("draw_image", json!({ "prompt": "a cat", "count": 2 })),
+            ("draw_image", json!({ "prompt": "a dog", "count": 2 })),
+        ])
+        .with_reply("here they are");
+    let app = TestApp::with_args(llm, &["--image-quota", "3"])?;
+
+    let mut body = app.events().await?;
+    let (_, res) = app.assist(b"voice").await?;
+    assert_eq!(res, json!({ "status": "done" }));
+
+    // only the last gallery of each block has every image
+    let events = read_events(&mut body).await?;
+    let mut galleries = std::collections::HashMap::new();
+    for v in events.iter().filter(|v| v.event == "reply") {
+        let images = v.data.matches("<img src='/assets/image/").count();
+        galleries.insert(v.block().to_string(), images);
+    }
+    assert_eq!(galleries.values().sum::<usize>(), 3);
+    Ok(())
+}
+
+#[tokio::test]
+async fn images_drawn_before_a_failure_are_kept() -> Result<()> {
+    let llm = FakeBackend::default()
+        .with_transcript("draw two cats")
+        .with_tool_call("draw_image", json!({ "prompt": "a cat", "count": 2 }))
+        .with_image_failures(1)
+        .with_reply("here is one cat")
+        .with_transcript("two more")
+        .with_tool_call("draw_image", json!({ "prompt": "a dog", "count": 2 }))
+        .with_reply("here is one dog");
+    let app = TestApp::with_args(llm, &["--llm-retries", "0", "--image-quota", "2"])?;
+
+    let mut body = app.events().await?;
+    let (_, res) = app.assist(b"voice").await?;
+    assert_eq!(res, json!({ "status": "done" }));
+    let events = read_events(&mut body).await?;
+    let gallery = events
+        .iter()
+        .rfind(|v| v.event == "reply" && v.data.contains("<img src='/assets/image/"));
+    assert_eq!(
+        gallery
+            .unwrap()
+            .data
+            .matches("<img src='/assets/image/")
+            .count(),
+        1
+    );
+
+    // the one drawn counts against the quota, even though the other one failed
+    let (_, res) = app.assist(b"voice").await?;
+    assert_eq!(res, json!({ "status": "done" }));
+    let events = read_events(&mut body).await?;
+    let gallery = events
+        .iter()
+        .rfind(|v| v.event == "reply" && v.data.contains("<img src='/assets/image/"));
+    assert_eq!(
+        gallery
+            .unwrap()
+            .data
+            .matches("<img src='/assets/image/")
+            .count(),
+        1
+    );
+
+    let req = Request::get("/usage?format=json").body(Body::empty())?;
+    let res = app.request(req).await?;
+    let body = hyper::body::to_bytes(res.into_body()).await?;
+    let report: Value = serde_json::from_slice(&body)?;
+    let usage = report["devices"][0]["usage"].as_array().unwrap();
+    let images = usage.iter().find(|v| v["model"] == "dall-e-3").unwrap();
+    assert_eq!(images["images"], 2);
+    Ok(())
+}
@@ -0,0 +1,143 @@
+mod common;
+
+use anyhow::Result;
+use axum::{
+    body::Body,
+    http::{header, Request},
+};
+use common::{names, read_events, TestApp};
+use q_bot::llm::FakeBackend;
+use serde_json::{json, Value};
+
+fn ids(events: &[common::SseEvent]) -> Vec<u64> {
+    events.iter().map(|v| v.id.parse().unwrap()).collect()
+}
+
+#[tokio::test]
+async fn event_ids_increase_per_device() -> Result<()> {
+    let llm = FakeBackend::default()
+        .with_transcript("hi")
+        .with_reply("hello there");
+    let app = TestApp::new(llm)?;
+
+    let mut body = app.events().await?;
+    app.assist(b"voice").await?;
+
+    let events = read_events(&mut body).await?;
+    let expected: Vec<u64> = (1..=events.len() as u64).collect();
+    assert_eq!(ids(&events), expected);
+    Ok(())
+}
+
+#[tokio::test]
+async fn reconnect_replays_missed_events() -> Result<()> {
+    let llm = FakeBackend::default()
+        .with_transcript("hi")
+        .with_reply("hello there");
+    let app = TestApp::new(llm)?;
+
+    let mut body = app.events().await?;
+    app.assist(b"voice").await?;
+    let events = read_events(&mut body).await?;
+    drop(body);
+
+    // the browser only saw the first three events before the connection dropped
+    let mut body = app.reconnect("3").await?;
+    let replayed = read_events(&mut body).await?;
+    assert_eq!(ids(&replayed), ids(&events[3..]));
+    assert_eq!(names(&replayed), names(&events[3..]));
+    assert!(replayed.last().unwrap().data.contains("<audio"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn reply_can_be_sent_while_disconnected() -> Result<()> {
+    let llm = FakeBackend::default()
+        .with_transcript("hi")
+        .with_reply("hello there");
+    let app = TestApp::new(llm)?;
+
+    drop(app.events().await?);
+    let (_, res) = app.assist(b"voice").await?;
+    assert_eq!(res["status"], "done");
+
+    let mut body = app.reconnect("0").await?;
+    let replayed = read_events(&mut body).await?;
+    assert_eq!(replayed[0].id, "1");
+    assert!(replayed.last().unwrap().data.contains("hello there"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn unknown_last_event_id_asks_for_resync() -> Result<()> {
+    let app = TestApp::new(FakeBackend::default())?;
+
+    let mut body = app.reconnect("42").await?;
+    let events = read_events(&mut body).await?;
+    assert_eq!(names(&events), vec!["resync"]);
+    assert_eq!(events[0].id, "0");
+    Ok(())
+}
+
+#[tokio::test]
+async fn assist_works_before_events_are_opened() -> Result<()> {
+    let llm = FakeBackend::default()
+        .with_transcript("hi")
+        .with_reply("hello there");
+    let app = TestApp::new(llm)?;
+
+    let (_, res) = app.assist(b"voice").await?;
+    assert_eq!(res["status"], "done");
+
+    // the page was loaded before the events, nothing is lost
+    let mut body = app.reconnect("0").await?;
+    let events = read_events(&mut body).await?;
+    assert_eq!(events[0].id, "1");
+    assert!(events.last().unwrap().data.contains("<audio"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn json_clients_get_structured_events() -> Result<()> {
+    let llm = FakeBackend::default()
+        .with_transcript("hi")
+        .with_reply("hello there")
+        .with_transcript("again")
+        .with_reply("hello again");
+    let app = TestApp::new(llm)?;
+
+    for (uri, accept) in [
+        ("/events?format=json", "text/event-stream"),
+        ("/events", "application/json"),
+    ] {
+        let req = Request::get(uri)
+            .header(header::COOKIE, app.cookie())
+            .header(header::ACCEPT, accept)
+            .body(Body::empty())?;
+        let mut body = app.request(req).await?.into_body();
+        app.assist(b"voice").await?;
+
+        let events = read_events(&mut body).await?;
+        let payloads = events
+            .iter()
+            .map(|v| serde_json::from_str::<Value>(&v.data))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (event, payload) in events.iter().zip(&payloads) {
+            assert_eq!(payload["version"], 1);
+            assert_eq!(payload["type"], event.event);
+        }
+        assert_eq!(
+            payloads[0]["data"],
+            json!({ "type": "processing", "data": "upload_audio" })
+        );
+        assert!(payloads[3]["data"]["content"].is_string());
+        let reply = &payloads.last().unwrap()["data"]["data"];
+        assert_eq!(reply["type"], "speech");
+        assert!(reply["data"]["url"]
+            .as_str()
+            .unwrap()
+            .starts_with("/assets/speech/"));
+        assert_eq!(reply["data"]["playlist"][0], reply["data"]["url"]);
+    }
+    Ok(())
+}
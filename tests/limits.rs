@@ -0,0 +1,73 @@
+mod common;
+
+use anyhow::Result;
+use common::{read_events, TestApp};
+use q_bot::llm::FakeBackend;
+use serde_json::json;
+use uuid::Uuid;
+
+#[tokio::test]
+async fn requests_over_the_rate_limit_are_turned_away() -> Result<()> {
+    let llm = FakeBackend::default()
+        .with_reply("hello there")
+        .with_reply("hello again");
+    let app = TestApp::with_args(llm, &["--device-rate-limit", "1"])?;
+
+    let mut body = app.events().await?;
+    let (status, _) = app.ask(json!({ "text": "hi" })).await?;
+    assert_eq!(status, 200);
+    let (status, res) = app.ask(json!({ "text": "hi again" })).await?;
+    assert_eq!((status, &res["code"]), (429, &json!("rate_limited")));
+
+    let events = read_events(&mut body).await?;
+    let signal = &events.last().unwrap().data;
+    assert!(signal.contains("data-limited"));
+    assert!(signal.contains("at most 1 a minute"));
+    // turned away before reaching the model
+    assert_eq!(app.llm.chat_requests().len(), 1);
+
+    // other devices have a rate of their own
+    let other = TestApp {
+        device_id: Uuid::new_v4().to_string(),
+        ..app.clone()
+    };
+    let (status, _) = other.ask(json!({ "text": "hi" })).await?;
+    assert_eq!(status, 200);
+    Ok(())
+}
+
+#[tokio::test]
+async fn daily_token_cap_stops_the_device() -> Result<()> {
+    let llm = FakeBackend::default().with_reply("hello there");
+    let app = TestApp::with_args(llm, &["--device-daily-tokens", "10"])?;
+
+    let (status, _) = app.ask(json!({ "text": "hi" })).await?;
+    assert_eq!(status, 200);
+    let (status, res) = app.ask(json!({ "text": "hi again" })).await?;
+    assert_eq!(status, 429);
+    assert!(res["message"]
+        .as_str()
+        .unwrap()
+        .contains("daily limit of 10 tokens"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn global_spending_cap_stops_every_device() -> Result<()> {
+    let llm = FakeBackend::default().with_reply("hello there");
+    let app = TestApp::with_args(llm, &["--global-daily-cost", "0.00001"])?;
+
+    let (status, _) = app.ask(json!({ "text": "hi" })).await?;
+    assert_eq!(status, 200);
+    let other = TestApp {
+        device_id: Uuid::new_v4().to_string(),
+        ..app.clone()
+    };
+    let (status, res) = other.ask(json!({ "text": "hi" })).await?;
+    assert_eq!(status, 429);
+    assert!(res["message"]
+        .as_str()
+        .unwrap()
+        .contains("shared daily spending cap"));
+    Ok(())
+}
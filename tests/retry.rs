@@ -0,0 +1,109 @@
+mod common;
+
+use anyhow::Result;
+use common::{read_events, signals, TestApp};
+use q_bot::llm::FakeBackend;
+use serde_json::json;
+use std::time::Duration;
+
+#[tokio::test]
+async fn flaky_steps_are_retried() -> Result<()> {
+    let llm = FakeBackend::default()
+        .with_failures(1)
+        .with_transcript("hi")
+        .with_reply("hello there");
+    let app = TestApp::new(llm)?;
+
+    let mut body = app.events().await?;
+    let (_, res) = app.assist(b"voice").await?;
+    assert_eq!(res, json!({ "status": "done" }));
+
+    let events = read_events(&mut body).await?;
+    assert!(signals(&events).contains(&"Retrying transcription, attempt 2".to_string()));
+    assert!(events.last().unwrap().data.contains("<audio"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn hung_calls_time_out() -> Result<()> {
+    let llm = FakeBackend::default()
+        .with_latency(Duration::from_millis(300))
+        .with_reply("too late");
+    let app = TestApp::with_args(llm, &["--thinking-timeout", "0.05", "--llm-retries", "1"])?;
+
+    let mut body = app.events().await?;
+    let (status, res) = app.ask(json!({ "text": "hi" })).await?;
+    assert_eq!((status, &res["code"]), (504, &json!("timeout")));
+
+    let events = read_events(&mut body).await?;
+    let signals = signals(&events);
+    assert!(signals.contains(&"Retrying thinking, attempt 2".to_string()));
+    assert!(signals.last().unwrap().contains("took too long"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn failing_model_falls_back_to_the_next() -> Result<()> {
+    let config = std::env::temp_dir().join(format!("qbot-{}.toml", uuid::Uuid::new_v4()));
+    std::fs::write(
+        &config,
+        "[models]\nchat_fallbacks = [\"gpt-4-1106-preview\"]\n",
+    )?;
+    let llm = FakeBackend::default()
+        .with_failures(2)
+        .with_reply("from the fallback");
+    let app = TestApp::with_args(
+        llm,
+        &["--config", config.to_str().unwrap(), "--llm-retries", "1"],
+    )?;
+
+    let mut body = app.events().await?;
+    let (_, res) = app.ask(json!({ "text": "hi" })).await?;
+    assert_eq!(res, json!({ "status": "done" }));
+
+    let models: Vec<_> = app
+        .llm
+        .chat_requests()
+        .iter()
+        .map(|v| v["model"].as_str().unwrap_or_default().to_string())
+        .collect();
+    assert_eq!(
+        models,
+        vec![
+            "gpt-3.5-turbo-1106",
+            "gpt-3.5-turbo-1106",
+            "gpt-4-1106-preview"
+        ]
+    );
+
+    let events = read_events(&mut body).await?;
+    assert!(signals(&events).contains(&"Retrying thinking with gpt-4-1106-preview".to_string()));
+    assert!(events.last().unwrap().data.contains("from the fallback"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn rejected_calls_are_not_retried() -> Result<()> {
+    let config = std::env::temp_dir().join(format!("qbot-{}.toml", uuid::Uuid::new_v4()));
+    std::fs::write(
+        &config,
+        "[models]\nchat_fallbacks = [\"gpt-4-1106-preview\"]\n",
+    )?;
+    let llm = FakeBackend::default()
+        .with_rejections(1)
+        .with_reply("never asked for");
+    let app = TestApp::with_args(
+        llm,
+        &["--config", config.to_str().unwrap(), "--llm-retries", "2"],
+    )?;
+
+    let mut body = app.events().await?;
+    let (status, res) = app.ask(json!({ "text": "hi" })).await?;
+    assert_eq!((status, &res["code"]), (502, &json!("rejected")));
+    // neither asked again nor handed to the fallback model
+    assert_eq!(app.llm.chat_requests().len(), 1);
+
+    let events = read_events(&mut body).await?;
+    assert!(!signals(&events).iter().any(|v| v.starts_with("Retrying")));
+    Ok(())
+}
@@ -0,0 +1,112 @@
+mod common;
+
+use anyhow::Result;
+use axum::{body::Body, http::Request};
+use common::{names, read_events, TestApp};
+use q_bot::{handlers::MAX_UPLOAD_BYTES, llm::FakeBackend};
+use serde_json::{json, Value};
+
+#[tokio::test]
+async fn typed_question_skips_transcription_and_speech() -> Result<()> {
+    let llm = FakeBackend::default()
+        .with_tool_call("answer", json!({ "prompt": "what is rust" }))
+        .with_stream(["Rust is a language"]);
+    let app = TestApp::new(llm)?;
+
+    let mut body = app.events().await?;
+    let (status, res) = app.ask(json!({ "text": "what is rust" })).await?;
+    assert_eq!(status, 200);
+    assert_eq!(res, json!({ "status": "done" }));
+
+    let events = read_events(&mut body).await?;
+    assert_eq!(
+        names(&events),
+        vec![
+            "input_skeleton",
+            "input",
+            "signal",
+            "reply_skeleton",
+            "signal",
+            "signal",
+            "reply",
+        ]
+    );
+    assert!(events[1].data.contains("what is rust"));
+    let reply = events.last().unwrap();
+    assert!(reply.data.contains("Rust is a language"));
+    assert!(!reply.data.contains("<audio"));
+
+    // the typed question is part of the conversation like a spoken one
+    let requests = app.llm.chat_requests();
+    let messages = requests[0]["messages"].as_array().unwrap();
+    assert_eq!(messages.last().unwrap()["content"], "what is rust");
+    Ok(())
+}
+
+#[tokio::test]
+async fn typed_question_can_ask_for_speech() -> Result<()> {
+    let llm = FakeBackend::default().with_reply("hello there");
+    let app = TestApp::new(llm)?;
+
+    let mut body = app.events().await?;
+    app.ask(json!({ "text": "hi", "speech": true })).await?;
+
+    let events = read_events(&mut body).await?;
+    let reply = events.last().unwrap();
+    assert_eq!(reply.event, "reply");
+    assert!(reply.data.contains("<audio"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn empty_question_signals_error() -> Result<()> {
+    let app = TestApp::new(FakeBackend::default())?;
+
+    let mut body = app.events().await?;
+    let (status, res) = app.ask(json!({ "text": "  " })).await?;
+    assert_eq!(status, 400);
+    assert_eq!(
+        res,
+        json!({ "status": "error", "code": "bad_input", "message": "expected some text" })
+    );
+
+    let events = read_events(&mut body).await?;
+    assert_eq!(names(&events), vec!["signal"]);
+    assert!(events[0].data.contains("expected some text"));
+    assert!(app.llm.chat_requests().is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn malformed_json_gets_a_json_error() -> Result<()> {
+    let app = TestApp::new(FakeBackend::default())?;
+
+    for (content_type, body) in [
+        ("application/json", r#"{"text": "#),
+        ("application/json", r#"{"speech": true}"#),
+        ("text/plain", r#"{"text": "hi"}"#),
+    ] {
+        let req = Request::post("/assistant/text")
+            .header("cookie", app.cookie())
+            .header("content-type", content_type)
+            .body(Body::from(body))?;
+        let res = app.request(req).await?;
+        assert!(res.status().is_client_error(), "{}", body);
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        let res: Value = serde_json::from_slice(&body)?;
+        assert_eq!(res["status"], "error");
+        assert_eq!(res["code"], "bad_input");
+        assert!(!res["message"].as_str().unwrap().is_empty());
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn oversized_uploads_are_too_large() -> Result<()> {
+    let app = TestApp::new(FakeBackend::default())?;
+
+    let (status, res) = app.assist(&vec![0; MAX_UPLOAD_BYTES + 1]).await?;
+    assert_eq!(status, 413);
+    assert_eq!(res["code"], "too_large");
+    Ok(())
+}
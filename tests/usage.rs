@@ -0,0 +1,65 @@
+mod common;
+
+use anyhow::Result;
+use axum::{body::Body, http::Request};
+use common::TestApp;
+use q_bot::{
+    llm::{FakeBackend, FAKE_AUDIO_SECONDS, FAKE_TOKENS},
+    usage::DEVICE_PREFIX_LEN,
+};
+use serde_json::Value;
+
+async fn get(app: &TestApp, uri: &str) -> Result<(u16, String)> {
+    let req = Request::get(uri).body(Body::empty())?;
+    let res = app.request(req).await?;
+    let status = res.status().as_u16();
+    let body = hyper::body::to_bytes(res.into_body()).await?;
+
+    Ok((status, String::from_utf8(body.to_vec())?))
+}
+
+#[tokio::test]
+async fn usage_is_recorded_per_device_and_model() -> Result<()> {
+    let llm = FakeBackend::default()
+        .with_transcript("hi")
+        .with_reply("hello there");
+    let app = TestApp::new(llm)?;
+    app.assist(b"voice").await?;
+
+    let (status, report) = get(&app, "/usage?format=json").await?;
+    assert_eq!(status, 200);
+    let report: Value = serde_json::from_str(&report)?;
+    let devices = report["devices"].as_array().unwrap();
+    assert_eq!(devices.len(), 1);
+    // only the start of the id, the whole of it is as good as the device's cookie
+    assert_eq!(devices[0]["device"], &app.device_id[..DEVICE_PREFIX_LEN]);
+    assert!(!report.to_string().contains(&app.device_id));
+
+    let usage = devices[0]["usage"].as_array().unwrap();
+    let model = |name: &str| usage.iter().find(|v| v["model"] == name).unwrap();
+    assert_eq!(model("whisper-1")["audio_seconds"], FAKE_AUDIO_SECONDS);
+    let chat = model("gpt-3.5-turbo-1106");
+    assert_eq!(chat["prompt_tokens"], FAKE_TOKENS.0);
+    assert_eq!(chat["completion_tokens"], FAKE_TOKENS.1);
+    assert_eq!(model("tts-1")["speech_chars"], "hello there".len());
+    assert!(report["cost"].as_f64().unwrap() > 0.0);
+
+    let (status, page) = get(&app, "/usage").await?;
+    assert_eq!(status, 200);
+    assert!(page.contains(&app.device_id[..DEVICE_PREFIX_LEN]));
+    assert!(!page.contains(&app.device_id));
+    assert!(page.contains("whisper-1"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn usage_page_without_any_usage() -> Result<()> {
+    let app = TestApp::new(FakeBackend::default())?;
+
+    let (status, report) = get(&app, "/usage?format=json&days=1").await?;
+    assert_eq!(status, 200);
+    let report: Value = serde_json::from_str(&report)?;
+    assert_eq!(report["devices"], serde_json::json!([]));
+    assert_eq!(report["cost"], 0.0);
+    Ok(())
+}
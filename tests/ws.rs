@@ -0,0 +1,167 @@
+mod common;
+
+use std::time::Duration;
+
+use anyhow::Result;
+use common::TestApp;
+use futures::{SinkExt, StreamExt};
+use q_bot::{handlers::MAX_UPLOAD_BYTES, llm::FakeBackend};
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{client::IntoClientRequest, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+async fn connect(app: &TestApp) -> Result<Socket> {
+    let addr = app.serve().await?;
+    let mut req = format!("ws://{}/ws", addr).into_client_request()?;
+    req.headers_mut().insert("cookie", app.cookie().parse()?);
+    let (socket, _) = connect_async(req).await?;
+
+    Ok(socket)
+}
+
+async fn send(socket: &mut Socket, msg: Value) -> Result<()> {
+    socket.send(Message::Text(msg.to_string())).await?;
+    Ok(())
+}
+
+/// read frames until the socket has been quiet for a while
+async fn read_frames(socket: &mut Socket) -> Result<Vec<Value>> {
+    let mut frames = vec![];
+    while let Ok(Some(msg)) = tokio::time::timeout(Duration::from_millis(300), socket.next()).await
+    {
+        if let Message::Text(text) = msg? {
+            frames.push(serde_json::from_str(&text)?);
+        }
+    }
+
+    Ok(frames)
+}
+
+fn types(frames: &[Value]) -> Vec<&str> {
+    frames.iter().filter_map(|v| v["type"].as_str()).collect()
+}
+
+#[tokio::test]
+async fn audio_chunks_are_assisted_on_end() -> Result<()> {
+    let llm = FakeBackend::default()
+        .with_transcript("hi")
+        .with_reply("hello there");
+    let app = TestApp::new(llm)?;
+    let mut socket = connect(&app).await?;
+
+    socket.send(Message::Binary(b"voi".to_vec())).await?;
+    socket.send(Message::Binary(b"ce".to_vec())).await?;
+    send(&mut socket, json!({ "type": "end" })).await?;
+
+    let frames = read_frames(&mut socket).await?;
+    assert_eq!(
+        types(&frames),
+        vec![
+            "signal",
+            "signal",
+            "input_skeleton",
+            "input",
+            "signal",
+            "reply_skeleton",
+            "signal",
+            "reply",
+            "signal",
+            "reply",
+        ]
+    );
+    assert_eq!(frames[0]["data"]["data"], "upload_audio");
+    assert_eq!(frames[3]["data"]["content"], "hi");
+    assert!(frames[7].to_string().contains("hello there"));
+    assert_eq!(frames[9]["data"]["data"]["type"], "speech");
+
+    // the same ids the sse stream uses
+    let ids: Vec<_> = frames.iter().map(|v| v["id"].as_u64().unwrap()).collect();
+    assert!(ids.windows(2).all(|v| v[0] < v[1]));
+    Ok(())
+}
+
+#[tokio::test]
+async fn text_messages_follow_the_mode() -> Result<()> {
+    let llm = FakeBackend::default().with_reply("hello there");
+    let app = TestApp::new(llm)?;
+    let mut socket = connect(&app).await?;
+
+    send(&mut socket, json!({ "type": "mode", "speech": false })).await?;
+    send(&mut socket, json!({ "type": "text", "text": "hi" })).await?;
+
+    let frames = read_frames(&mut socket).await?;
+    assert_eq!(
+        types(&frames),
+        vec![
+            "input_skeleton",
+            "input",
+            "signal",
+            "reply_skeleton",
+            "reply"
+        ]
+    );
+    assert!(frames[4].to_string().contains("hello there"));
+    assert_eq!(frames[4]["data"]["data"]["data"]["url"], "");
+    Ok(())
+}
+
+#[tokio::test]
+async fn invalid_messages_signal_error() -> Result<()> {
+    let app = TestApp::new(FakeBackend::default())?;
+    let mut socket = connect(&app).await?;
+
+    send(&mut socket, json!({ "type": "dance" })).await?;
+    send(&mut socket, json!({ "type": "end" })).await?;
+
+    let frames = read_frames(&mut socket).await?;
+    let errors: Vec<_> = frames
+        .iter()
+        .filter(|v| v["data"]["type"] == "error")
+        .map(|v| v["data"]["data"].as_str().unwrap_or_default())
+        .collect();
+    assert_eq!(errors.len(), 2);
+    assert!(errors[0].starts_with("invalid message"));
+    assert_eq!(errors[1], "expected some audio");
+    Ok(())
+}
+
+#[tokio::test]
+async fn oversized_audio_closes_the_socket() -> Result<()> {
+    let app = TestApp::new(FakeBackend::default())?;
+    let mut socket = connect(&app).await?;
+
+    let chunk = vec![0u8; MAX_UPLOAD_BYTES / 2 + 1];
+    socket.send(Message::Binary(chunk.clone())).await?;
+    socket.send(Message::Binary(chunk)).await?;
+
+    let mut error = None;
+    let mut closed = None;
+    while let Ok(Some(Ok(msg))) = tokio::time::timeout(Duration::from_secs(1), socket.next()).await
+    {
+        match msg {
+            Message::Text(text) => {
+                let frame: Value = serde_json::from_str(&text)?;
+                if frame["data"]["type"] == "error" {
+                    error = Some(frame);
+                }
+            }
+            Message::Close(frame) => closed = frame,
+            _ => {}
+        }
+    }
+    let error = error.unwrap();
+    assert!(error["data"]["data"]
+        .as_str()
+        .unwrap()
+        .contains("audio larger than"));
+    assert_eq!(u16::from(closed.unwrap().code), 1009);
+    // nothing was sent to the model
+    assert!(app.llm.chat_requests().is_empty());
+    Ok(())
+}